@@ -0,0 +1,19 @@
+//! Helpers that look things up over HTTP, enabled with the `http` feature
+
+use reqwest::{blocking::Client, header};
+
+/// Returns the size in bytes of the file at `url`, from the `Content-Length` of a `HEAD` request.
+/// `None` if the server doesn't say, or the request fails
+pub fn head_size(client: &Client, url: &str) -> Option<u64> {
+    client
+        .head(url)
+        .send()
+        .ok()
+        .filter(|response| response.status().is_success())?
+        .headers()
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
@@ -0,0 +1,461 @@
+//! Types for writing Apocalypse Library plugins.
+//!
+//! A plugin outputs a `VersionRecord` followed by `LibraryItem`s as JSON, one per line. These
+//! types serialize to exactly the format the downloader reads, see `SCHEMA`, so plugins don't need
+//! to re-implement it by hand:
+//!
+//! ```
+//! use apocalypse_library_types::{Document, LibraryItem, VersionRecord};
+//!
+//! println!("{}", VersionRecord::current().to_json_line());
+//! let item: LibraryItem = Document::builder("Arch Linux", "https://example.com/arch.iso")
+//!     .size(1_200_000_000)
+//!     .path("Linux/ISOs")
+//!     .build()
+//!     .into();
+//! println!("{}", item.to_json_line());
+//! ```
+//!
+//! Output without a `VersionRecord` is read as version 1, see the `v1` module.
+
+#[cfg(feature = "http")]
+pub mod http;
+pub mod v1;
+
+use std::io::{self, Write};
+
+use serde::{Deserialize, Serialize};
+
+/// The version of the plugin output format these types describe, bumped whenever it changes in a
+/// way that older versions of the downloader can't read
+///
+/// Version 2 made `enabled` and a Category's `single_selection` optional, and added the
+/// descriptive fields of a `Document` such as `description` and `license`, along with `id`,
+/// `requires`, `recommends` and `priority`. It also replaced the `include` and `exclude` lists of
+/// `RsyncFilters` with ordered `rules`
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// The `priority` of a Document that doesn't set one
+pub const DEFAULT_PRIORITY: u8 = 5;
+
+/// A JSON schema describing each line of plugin output, for plugin authors and their tooling
+///
+/// The downloader reads output with these serde types rather than validating it against the
+/// schema, `tests/schema.rs` checks that the two agree
+pub const SCHEMA: &str = include_str!("../schema/library.schema.json");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// Declares which version of the format the output after it uses, such as
+/// `{"schema_version": 2}`. Output starts as version 1 until one of these is found
+pub struct VersionRecord {
+    /// The version of the format, see `SCHEMA_VERSION`
+    pub schema_version: u32,
+}
+
+impl VersionRecord {
+    /// Returns the record for the version these types describe
+    #[must_use]
+    pub const fn current() -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+        }
+    }
+
+    /// Returns the record as a single line of JSON, as plugins output it
+    #[must_use]
+    pub fn to_json_line(&self) -> String {
+        format!("{{\"schema_version\":{}}}", self.schema_version)
+    }
+}
+
+/// Used for fields that default to true when they are left out
+const fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Either a `Document` or a `Category` of more items
+// Most items are Documents, so boxing them would only add an allocation per item
+#[allow(clippy::large_enum_variant)]
+pub enum LibraryItem {
+    Document(Document),
+    Category(Category),
+}
+
+impl LibraryItem {
+    /// Returns the item as a single line of JSON, as plugins output it
+    #[must_use]
+    pub fn to_json_line(&self) -> String {
+        // Every field is a plain string, number, bool or list of them, so this can't fail
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Writes the item to `writer` as a single line of JSON, followed by a newline
+    ///
+    /// # Errors
+    ///
+    /// Returns any error from writing to `writer`
+    pub fn write_line(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "{}", self.to_json_line())
+    }
+}
+
+impl From<Document> for LibraryItem {
+    fn from(doc: Document) -> Self {
+        Self::Document(doc)
+    }
+}
+
+impl From<Category> for LibraryItem {
+    fn from(cat: Category) -> Self {
+        Self::Category(cat)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+/// The download method to use for a `Document`
+pub enum DownloadType {
+    /// Download files with HTTP(s) GET requests
+    Http,
+    /// Download files by running the rsync application
+    Rsync,
+    /// Supports either HTTP GET or Rsync. Prefers Rsync by default
+    Either,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// A single rsync filter rule, such as `{"exclude": "*debug*"}`
+pub enum FilterRule {
+    /// Files matching the rsync pattern are downloaded
+    Include(String),
+    /// Files matching the rsync pattern are skipped
+    Exclude(String),
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Rules limiting which files of a `Document` are downloaded. These are passed directly to rsync,
+/// and checked against the file name when falling back to HTTP
+pub struct RsyncFilters {
+    /// rsync filter rules, checked in order with the first one that matches a file deciding if it
+    /// is downloaded. Files that match none are downloaded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<FilterRule>,
+    /// Files larger than this many bytes are skipped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// The total size in bytes of the files that pass the filters, as far as the plugin knows.
+    /// Shown until the downloader measures it with a rsync dry run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// A File or Group of files to download
+pub struct Document {
+    /// A stable identifier for the Document that other items refer to it by, such as
+    /// `kiwix-tools`. Unlike `name` it should never change. Documents without one are identified
+    /// by their `url`, and Documents with the same id from different plugins are shown once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The name of the Document(s)
+    pub name: String,
+    /// The path of the File(s) to get
+    pub url: String,
+    /// The total size of the File(s) in bytes
+    pub size: u64,
+    /// The method to use to download the File(s)
+    pub download_type: DownloadType,
+    /// The MIME type of the File, used to sanity check HTTP downloads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    /// The url of a `.zsync` control file for the File, used to only download the changed parts
+    /// of a File when a previous version of it exists locally
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zsync_url: Option<String>,
+    /// Rules limiting which of the File(s) are downloaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filters: Option<RsyncFilters>,
+    /// The Categories this Document belongs in, separated by `/`, such as `Linux/Arch/Mirrors`.
+    /// Lets plugins output flat records instead of nesting every Document in Categories
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Which levels of `path` only allow a single selection, missing levels default to false
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub single_selection: Vec<bool>,
+    /// Should these File(s) be downloaded by default
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// What the Document contains, shown to the user when they look at its details
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The language of the content, as a code such as `en` or `pt-BR`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// The license the content is distributed under, such as `CC-BY-SA-4.0`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    /// Who publishes the content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    /// When this version of the content was published, such as `2024-01-15` or `2024-01`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<String>,
+    /// Keywords describing the content
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// The url of a page about the content, rather than the download itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<String>,
+    /// The `id`s of items this Document is useless without, such as a reader for its format.
+    /// These are enabled along with it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requires: Vec<String>,
+    /// The `id`s of items that go well with this Document, these are only suggested
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recommends: Vec<String>,
+    /// How important the Document is, from 1 to 10, used to pick what fits on a drive of a given
+    /// size. Defaults to `DEFAULT_PRIORITY`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u8>,
+}
+
+impl Document {
+    /// Starts building an enabled Document called `name`, downloaded from `url` over HTTP
+    pub fn builder(name: impl Into<String>, url: impl Into<String>) -> DocumentBuilder {
+        DocumentBuilder {
+            doc: Self {
+                id: None,
+                name: name.into(),
+                url: url.into(),
+                size: 0,
+                download_type: DownloadType::Http,
+                mime_type: None,
+                zsync_url: None,
+                filters: None,
+                path: None,
+                single_selection: Vec::new(),
+                enabled: true,
+                description: None,
+                language: None,
+                license: None,
+                publisher: None,
+                published: None,
+                tags: Vec::new(),
+                homepage: None,
+                requires: Vec::new(),
+                recommends: Vec::new(),
+                priority: None,
+            },
+        }
+    }
+}
+
+/// Builds a `Document`, see `Document::builder`
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct DocumentBuilder {
+    doc: Document,
+}
+
+impl DocumentBuilder {
+    /// Sets the stable identifier other items refer to the Document by
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.doc.id = Some(id.into());
+        self
+    }
+
+    /// Sets the total size of the File(s) in bytes
+    pub const fn size(mut self, size: u64) -> Self {
+        self.doc.size = size;
+        self
+    }
+
+    /// Sets how the File(s) are downloaded
+    pub const fn download_type(mut self, download_type: DownloadType) -> Self {
+        self.doc.download_type = download_type;
+        self
+    }
+
+    /// Sets the MIME type that HTTP downloads are checked against
+    pub fn mime_type(mut self, mime_type: impl Into<String>) -> Self {
+        self.doc.mime_type = Some(mime_type.into());
+        self
+    }
+
+    /// Sets the url of a `.zsync` control file for the File
+    pub fn zsync_url(mut self, zsync_url: impl Into<String>) -> Self {
+        self.doc.zsync_url = Some(zsync_url.into());
+        self
+    }
+
+    /// Sets the rules limiting which of the File(s) are downloaded
+    pub fn filters(mut self, filters: RsyncFilters) -> Self {
+        self.doc.filters = Some(filters);
+        self
+    }
+
+    /// Sets the Categories the Document is placed in, separated by `/`
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.doc.path = Some(path.into());
+        self
+    }
+
+    /// Sets which levels of the path only allow a single selection
+    pub fn single_selection(mut self, single_selection: Vec<bool>) -> Self {
+        self.doc.single_selection = single_selection;
+        self
+    }
+
+    /// Sets if the Document is downloaded by default
+    pub const fn enabled(mut self, enabled: bool) -> Self {
+        self.doc.enabled = enabled;
+        self
+    }
+
+    /// Sets the description of what the Document contains
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.doc.description = Some(description.into());
+        self
+    }
+
+    /// Sets the language code of the content
+    pub fn language(mut self, language: impl Into<String>) -> Self {
+        self.doc.language = Some(language.into());
+        self
+    }
+
+    /// Sets the license the content is distributed under
+    pub fn license(mut self, license: impl Into<String>) -> Self {
+        self.doc.license = Some(license.into());
+        self
+    }
+
+    /// Sets who publishes the content
+    pub fn publisher(mut self, publisher: impl Into<String>) -> Self {
+        self.doc.publisher = Some(publisher.into());
+        self
+    }
+
+    /// Sets when this version of the content was published
+    pub fn published(mut self, published: impl Into<String>) -> Self {
+        self.doc.published = Some(published.into());
+        self
+    }
+
+    /// Adds a keyword describing the content
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.doc.tags.push(tag.into());
+        self
+    }
+
+    /// Sets the url of a page about the content
+    pub fn homepage(mut self, homepage: impl Into<String>) -> Self {
+        self.doc.homepage = Some(homepage.into());
+        self
+    }
+
+    /// Adds the `id` of an item the Document is useless without
+    pub fn require(mut self, id: impl Into<String>) -> Self {
+        self.doc.requires.push(id.into());
+        self
+    }
+
+    /// Adds the `id` of an item that goes well with the Document
+    pub fn recommend(mut self, id: impl Into<String>) -> Self {
+        self.doc.recommends.push(id.into());
+        self
+    }
+
+    /// Sets how important the Document is, from 1 to 10
+    pub const fn priority(mut self, priority: u8) -> Self {
+        self.doc.priority = Some(priority);
+        self
+    }
+
+    /// Returns the finished Document
+    #[must_use]
+    pub fn build(self) -> Document {
+        self.doc
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// A group of items, which may be either Documents or Categories of their own
+pub struct Category {
+    /// A stable identifier for the Category, Categories with the same id are merged even if their
+    /// names differ. Categories without one are merged by name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The name of the category
+    pub name: String,
+    /// The items contained in the category
+    pub items: Vec<LibraryItem>,
+    /// Can only one item be selected at a time
+    #[serde(default)]
+    pub single_selection: bool,
+    /// Should the items be downloaded by default
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+impl Category {
+    /// Starts building an enabled Category called `name`
+    pub fn builder(name: impl Into<String>) -> CategoryBuilder {
+        CategoryBuilder {
+            cat: Self {
+                id: None,
+                name: name.into(),
+                items: Vec::new(),
+                single_selection: false,
+                enabled: true,
+            },
+        }
+    }
+}
+
+/// Builds a `Category`, see `Category::builder`
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct CategoryBuilder {
+    cat: Category,
+}
+
+impl CategoryBuilder {
+    /// Sets the stable identifier the Category is merged by
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.cat.id = Some(id.into());
+        self
+    }
+
+    /// Adds an item to the Category
+    pub fn item(mut self, item: impl Into<LibraryItem>) -> Self {
+        self.cat.items.push(item.into());
+        self
+    }
+
+    /// Adds every item in `items` to the Category
+    pub fn items<I: Into<LibraryItem>>(mut self, items: impl IntoIterator<Item = I>) -> Self {
+        self.cat.items.extend(items.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets if only one item can be selected at a time
+    pub const fn single_selection(mut self, single_selection: bool) -> Self {
+        self.cat.single_selection = single_selection;
+        self
+    }
+
+    /// Sets if the items are downloaded by default
+    pub const fn enabled(mut self, enabled: bool) -> Self {
+        self.cat.enabled = enabled;
+        self
+    }
+
+    /// Returns the finished Category
+    #[must_use]
+    pub fn build(self) -> Category {
+        self.cat
+    }
+}
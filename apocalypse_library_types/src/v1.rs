@@ -0,0 +1,117 @@
+//! Version 1 of the plugin output format, used by output without a `VersionRecord`. Every field
+//! the current version has defaults for was required, these convert into the current types
+
+use serde::Deserialize;
+
+use crate::{DownloadType, FilterRule};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+/// Either a `Document` or a `Category` of more items
+pub enum LibraryItem {
+    Document(Document),
+    Category(Category),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+/// A File or Group of files to download
+pub struct Document {
+    pub name: String,
+    pub url: String,
+    pub size: u64,
+    pub download_type: DownloadType,
+    #[serde(default)]
+    pub mime_type: Option<String>,
+    #[serde(default)]
+    pub zsync_url: Option<String>,
+    #[serde(default)]
+    pub filters: Option<RsyncFilters>,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub single_selection: Vec<bool>,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+/// Rules limiting which files of a `Document` are downloaded, every `include` is checked before
+/// any `exclude`
+pub struct RsyncFilters {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+/// A group of items, which may be either Documents or Categories of their own
+pub struct Category {
+    pub name: String,
+    pub items: Vec<LibraryItem>,
+    pub single_selection: bool,
+    pub enabled: bool,
+}
+
+impl From<LibraryItem> for crate::LibraryItem {
+    fn from(item: LibraryItem) -> Self {
+        match item {
+            LibraryItem::Document(doc) => Self::Document(doc.into()),
+            LibraryItem::Category(cat) => Self::Category(cat.into()),
+        }
+    }
+}
+
+impl From<RsyncFilters> for crate::RsyncFilters {
+    fn from(filters: RsyncFilters) -> Self {
+        let include = filters.include.into_iter().map(FilterRule::Include);
+        let exclude = filters.exclude.into_iter().map(FilterRule::Exclude);
+        Self {
+            rules: include.chain(exclude).collect(),
+            max_size: filters.max_size,
+            size: filters.size,
+        }
+    }
+}
+
+impl From<Document> for crate::Document {
+    fn from(doc: Document) -> Self {
+        Self {
+            id: None,
+            name: doc.name,
+            url: doc.url,
+            size: doc.size,
+            download_type: doc.download_type,
+            mime_type: doc.mime_type,
+            zsync_url: doc.zsync_url,
+            filters: doc.filters.map(Into::into),
+            path: doc.path,
+            single_selection: doc.single_selection,
+            enabled: doc.enabled,
+            description: None,
+            language: None,
+            license: None,
+            publisher: None,
+            published: None,
+            tags: Vec::new(),
+            homepage: None,
+            requires: Vec::new(),
+            recommends: Vec::new(),
+            priority: None,
+        }
+    }
+}
+
+impl From<Category> for crate::Category {
+    fn from(cat: Category) -> Self {
+        Self {
+            id: None,
+            name: cat.name,
+            items: cat.items.into_iter().map(Into::into).collect(),
+            single_selection: cat.single_selection,
+            enabled: cat.enabled,
+        }
+    }
+}
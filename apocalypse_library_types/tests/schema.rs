@@ -0,0 +1,145 @@
+//! Checks that `SCHEMA` describes the same fields as the serde types, as it is written by hand
+
+use apocalypse_library_types::{
+    Category, Document, DownloadType, FilterRule, LibraryItem, RsyncFilters, VersionRecord, SCHEMA,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+/// Returns the schema definition called `name`
+fn definition(name: &str) -> Value {
+    let schema: Value = serde_json::from_str(SCHEMA).expect("the schema should be valid JSON");
+    schema["definitions"][name].clone()
+}
+
+/// Returns the keys of a JSON object, sorted
+fn keys(object: &Map<String, Value>) -> Vec<String> {
+    let mut keys: Vec<String> = object.keys().cloned().collect();
+    keys.sort_unstable();
+    keys
+}
+
+/// Checks that the definition called `name` has exactly the properties that `full`, a value with
+/// every field set, serializes with. Then checks that only the properties the definition requires
+/// are needed to deserialize `T`
+fn check_object<T: DeserializeOwned>(name: &str, full: &Value) {
+    let definition = definition(name);
+    let properties = definition["properties"]
+        .as_object()
+        .unwrap_or_else(|| panic!("{name} should have properties"));
+    let full = full.as_object().expect("items should serialize to objects");
+    assert_eq!(keys(properties), keys(full), "properties of {name}");
+
+    let required: Vec<&str> = definition["required"]
+        .as_array()
+        .map(|required| required.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let minimal: Map<String, Value> = full
+        .iter()
+        .filter(|(key, _)| required.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    serde_json::from_value::<T>(Value::Object(minimal.clone()))
+        .unwrap_or_else(|err| panic!("{name} with only the required properties: {err}"));
+    for key in required {
+        let mut missing = minimal.clone();
+        missing.remove(key);
+        assert!(
+            serde_json::from_value::<T>(Value::Object(missing)).is_err(),
+            "{name} should require {key}"
+        );
+    }
+}
+
+#[test]
+fn version_record_matches_schema() {
+    let full = serde_json::to_value(VersionRecord::current()).unwrap();
+    check_object::<VersionRecord>("VersionRecord", &full);
+}
+
+#[test]
+fn document_matches_schema() {
+    let filters = RsyncFilters {
+        rules: vec![FilterRule::Include("*.iso".into())],
+        max_size: Some(1),
+        size: Some(1),
+    };
+    let doc = Document::builder("Arch Linux", "https://example.com/arch.iso")
+        .id("arch")
+        .size(1)
+        .mime_type("application/x-iso9660-image")
+        .zsync_url("https://example.com/arch.iso.zsync")
+        .filters(filters.clone())
+        .path("Linux")
+        .single_selection(vec![true])
+        .enabled(false)
+        .description("An installer")
+        .language("en")
+        .license("GPL-2.0")
+        .publisher("Arch Linux")
+        .published("2024-01")
+        .tag("linux")
+        .homepage("https://archlinux.org")
+        .require("kiwix-tools")
+        .recommend("wikipedia")
+        .priority(7)
+        .build();
+    check_object::<Document>("Document", &serde_json::to_value(&doc).unwrap());
+    check_object::<RsyncFilters>("RsyncFilters", &serde_json::to_value(&filters).unwrap());
+}
+
+#[test]
+fn category_matches_schema() {
+    let cat = Category::builder("Linux")
+        .id("linux")
+        .item(Document::builder("Arch Linux", "https://example.com/arch.iso").build())
+        .single_selection(true)
+        .enabled(false)
+        .build();
+    check_object::<Category>("Category", &serde_json::to_value(&cat).unwrap());
+}
+
+#[test]
+fn enums_match_schema() {
+    let variants = |name: &str| -> Vec<Value> {
+        definition(name)["oneOf"]
+            .as_array()
+            .unwrap_or_else(|| panic!("{name} should have variants"))
+            .iter()
+            .map(|variant| variant["required"][0].clone())
+            .collect()
+    };
+    let item = |item: LibraryItem| serde_json::to_value(item).unwrap();
+    let doc = item(
+        Document::builder("Arch Linux", "https://example.com/arch.iso")
+            .build()
+            .into(),
+    );
+    let cat = item(Category::builder("Linux").build().into());
+    let tags: Vec<Value> = [doc, cat]
+        .iter()
+        .map(|item| Value::String(keys(item.as_object().unwrap())[0].clone()))
+        .collect();
+    assert_eq!(variants("LibraryItem"), tags);
+
+    let rules = serde_json::to_value([
+        FilterRule::Include(String::new()),
+        FilterRule::Exclude(String::new()),
+    ])
+    .unwrap();
+    let tags: Vec<Value> = rules
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|rule| Value::String(keys(rule.as_object().unwrap())[0].clone()))
+        .collect();
+    assert_eq!(variants("FilterRule"), tags);
+
+    let types = serde_json::to_value([
+        DownloadType::Http,
+        DownloadType::Rsync,
+        DownloadType::Either,
+    ])
+    .unwrap();
+    assert_eq!(definition("DownloadType")["enum"], types);
+}
@@ -0,0 +1,433 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+};
+
+use crate::types::{Category, LibraryItem};
+
+/// A `Document` in the library that could be picked when fitting to a budget
+struct Candidate {
+    /// The stable id of the Document
+    id: String,
+    /// The name of the Document, to note what pulled in its requirements
+    name: String,
+    /// The size of the Document in bytes
+    size: u64,
+    /// How important the Document is, from 1 to 10
+    priority: u8,
+    /// The ids of the items the Document requires
+    requires: Vec<String>,
+    /// Each single selection Category the Document is in, numbered in the order they were found,
+    /// along with which of its items the Document is in
+    choices: Vec<(usize, usize)>,
+    /// If the Document can be downloaded at all
+    can_download: bool,
+}
+
+/// The Documents picked so far while fitting to a budget
+#[derive(Clone, Default)]
+struct Selection {
+    /// The index of each picked candidate, along with the name of the Document that pulled it in
+    /// if it was only picked as a requirement
+    picked: HashMap<usize, Option<String>>,
+    /// Which item has been picked in each single selection Category
+    choices: HashMap<usize, usize>,
+    /// The total size of the picked Documents in bytes
+    used: u64,
+    /// What the picked Documents are worth, the size of each multiplied by its priority
+    value: u128,
+}
+
+/// What fitting the library to a budget picked, see `fit`
+#[derive(Debug, Clone)]
+pub struct Fit {
+    /// How many Documents were enabled, including those pulled in as requirements
+    pub picked: usize,
+    /// How many of those were only enabled as requirements
+    pub required: usize,
+    /// The total size of the enabled Documents in bytes
+    pub used: u64,
+    /// The names of the Documents that were left out because their size isn't known
+    pub unknown: Vec<String>,
+    /// The names of the Documents that were left out because something they require, directly or
+    /// through other requirements, isn't in the library or its size isn't known
+    pub unmet: Vec<String>,
+}
+
+/// Parses a drive capacity such as `256GB`, `1 TB`, `4T` or `500GiB`. Units are powers of 1000, as
+/// drives are sold by, unless they are written as `KiB`, `MiB`, `GiB` or `TiB`. A plain number is
+/// in bytes
+pub fn parse_capacity(str: &str) -> Option<u64> {
+    let str = str.trim();
+    let split = str
+        .find(|char: char| !(char.is_ascii_digit() || char == '.'))
+        .unwrap_or(str.len());
+    let (number, unit) = str.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let unit = unit.trim().to_ascii_lowercase();
+    let mut chars = unit.chars();
+    let power = match chars.next() {
+        None | Some('b') => 0,
+        Some('k') => 1,
+        Some('m') => 2,
+        Some('g') => 3,
+        Some('t') => 4,
+        Some('p') => 5,
+        _ => return None,
+    };
+    let base: f64 = match chars.as_str() {
+        "" | "b" => 1000.0,
+        "i" | "ib" if power > 0 => 1024.0,
+        _ => return None,
+    };
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Some((number * base.powi(power)) as u64)
+}
+
+/// Enables the best selection of `Documents` in the library under `root` that fits in `capacity`
+/// bytes, disabling everything else. The most important Documents are picked first, and the
+/// selection that fills the most space with the most important content is kept. Only one item is
+/// picked in each single selection Category, and the Documents that a picked Document requires
+/// are picked along with it, noting which Document they are required by.
+///
+/// Finding the very best selection is too slow for large libraries, so this picks greedily in
+/// order of priority, once with the largest Documents of each priority first and once with the
+/// smallest first, and keeps whichever is worth more.
+///
+/// Documents with a size of zero are left out, as their size isn't known and they would always
+/// seem to fit. So are Documents that require anything that can't be picked, as picking them
+/// without it would leave the selection incomplete
+pub fn fit(root: &mut Category, capacity: u64) -> Fit {
+    let mut candidates = Vec::new();
+    let mut groups = 0;
+    collect(&root.items, &[], &mut groups, &mut candidates);
+    let unknown = candidates
+        .iter()
+        .filter(|candidate| candidate.can_download && candidate.size == 0)
+        .map(|candidate| candidate.name.clone())
+        .collect();
+    let usable = usable(&candidates);
+    let unmet = candidates
+        .iter()
+        .zip(&usable)
+        .filter(|(candidate, usable)| candidate.can_download && candidate.size > 0 && !**usable)
+        .map(|(candidate, _)| candidate.name.clone())
+        .collect();
+    let by_id: HashMap<&str, usize> = candidates
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| usable[*index])
+        .map(|(index, candidate)| (candidate.id.as_str(), index))
+        .collect();
+
+    let mut order: Vec<usize> = (0..candidates.len())
+        .filter(|index| usable[*index])
+        .collect();
+    // Largest first picks the most complete of the alternatives in single selection Categories
+    order.sort_by_key(|index| {
+        let candidate = &candidates[*index];
+        (Reverse(candidate.priority), Reverse(candidate.size))
+    });
+    let largest = pick(&candidates, &by_id, &order, capacity);
+    // Smallest first fits more items in when the largest leave space that nothing else fits in
+    order.sort_by_key(|index| {
+        let candidate = &candidates[*index];
+        (Reverse(candidate.priority), candidate.size)
+    });
+    let smallest = pick(&candidates, &by_id, &order, capacity);
+    let selection = if smallest.value > largest.value {
+        smallest
+    } else {
+        largest
+    };
+
+    let mut index = 0;
+    for item in &mut root.items {
+        apply(item, &selection.picked, &mut index);
+    }
+    Fit {
+        picked: selection.picked.len(),
+        required: selection.picked.values().filter(|by| by.is_some()).count(),
+        used: selection.used,
+        unknown,
+        unmet,
+    }
+}
+
+/// Returns which `candidates` can be picked: those that can be downloaded, have a known size, and
+/// only require candidates that can be picked themselves
+fn usable(candidates: &[Candidate]) -> Vec<bool> {
+    let mut usable: Vec<bool> = candidates
+        .iter()
+        .map(|candidate| candidate.can_download && candidate.size > 0)
+        .collect();
+    // Each pass rules out the candidates that require one ruled out by the last, until none are
+    let mut changed = true;
+    while changed {
+        let by_id: HashMap<&str, usize> = candidates
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| usable[*index])
+            .map(|(index, candidate)| (candidate.id.as_str(), index))
+            .collect();
+        changed = false;
+        for (index, candidate) in candidates.iter().enumerate() {
+            if usable[index]
+                && !candidate
+                    .requires
+                    .iter()
+                    .all(|id| by_id.contains_key(id.as_str()))
+            {
+                usable[index] = false;
+                changed = true;
+            }
+        }
+    }
+    usable
+}
+
+/// Adds a `Candidate` for every `Document` in `items`, in the order that `apply` visits them.
+/// `choices` are the single selection Categories that `items` are in, and `groups` counts how
+/// many have been found
+fn collect(
+    items: &[LibraryItem],
+    choices: &[(usize, usize)],
+    groups: &mut usize,
+    candidates: &mut Vec<Candidate>,
+) {
+    for item in items {
+        match item {
+            LibraryItem::Document(doc) => candidates.push(Candidate {
+                id: doc.id().to_string(),
+                name: doc.name().to_string(),
+                size: doc.size(),
+                priority: doc.priority(),
+                requires: doc.requires().to_vec(),
+                choices: choices.to_vec(),
+                can_download: doc.can_download(),
+            }),
+            LibraryItem::Category(cat) if cat.single_selection() => {
+                let group = *groups;
+                *groups += 1;
+                for (index, item) in cat.items.iter().enumerate() {
+                    let mut choices = choices.to_vec();
+                    choices.push((group, index));
+                    collect(std::slice::from_ref(item), &choices, groups, candidates);
+                }
+            }
+            LibraryItem::Category(cat) => collect(&cat.items, choices, groups, candidates),
+        }
+    }
+}
+
+/// Picks each candidate in `order` that still fits in `capacity` along with its requirements
+fn pick(
+    candidates: &[Candidate],
+    by_id: &HashMap<&str, usize>,
+    order: &[usize],
+    capacity: u64,
+) -> Selection {
+    let mut selection = Selection::default();
+    for index in order {
+        if !selection.picked.contains_key(index) {
+            selection.try_add(*index, candidates, by_id, capacity);
+        }
+    }
+    selection
+}
+
+impl Selection {
+    /// Picks the candidate at `index` along with everything it requires, if they all fit in
+    /// `capacity` and don't conflict with a single selection Category
+    fn try_add(
+        &mut self,
+        index: usize,
+        candidates: &[Candidate],
+        by_id: &HashMap<&str, usize>,
+        capacity: u64,
+    ) {
+        let mut choices = self.choices.clone();
+        let mut added = Vec::new();
+        let mut seen = HashSet::new();
+        let mut stack = vec![(index, None)];
+        while let Some((index, required_by)) = stack.pop() {
+            if self.picked.contains_key(&index) || !seen.insert(index) {
+                continue;
+            }
+            let candidate = &candidates[index];
+            for (group, choice) in &candidate.choices {
+                if choices.entry(*group).or_insert(*choice) != choice {
+                    return;
+                }
+            }
+            // Every requirement of a usable candidate is in `by_id`, see `usable`
+            stack.extend(
+                candidate
+                    .requires
+                    .iter()
+                    .filter_map(|id| by_id.get(id.as_str()))
+                    .map(|index| (*index, Some(candidate.name.clone()))),
+            );
+            added.push((index, required_by));
+        }
+        let size: u64 = added.iter().map(|(index, _)| candidates[*index].size).sum();
+        if self.used.saturating_add(size) > capacity {
+            return;
+        }
+        self.used += size;
+        for (index, required_by) in added {
+            let candidate = &candidates[index];
+            self.value += u128::from(candidate.priority) * u128::from(candidate.size);
+            self.picked.insert(index, required_by);
+        }
+        self.choices = choices;
+    }
+}
+
+/// Enables the `Documents` in `item` that were `picked`, and the Categories that contain any of
+/// them, disabling everything else. `index` counts the Documents visited so far, in the same
+/// order as `collect`. Returns if anything in `item` is enabled
+fn apply(
+    item: &mut LibraryItem,
+    picked: &HashMap<usize, Option<String>>,
+    index: &mut usize,
+) -> bool {
+    match item {
+        LibraryItem::Document(doc) => {
+            let found = picked.get(index);
+            *index += 1;
+            doc.enabled = found.is_some();
+            doc.set_required_by(found.cloned().flatten());
+            doc.enabled
+        }
+        LibraryItem::Category(cat) => {
+            let mut enabled = false;
+            for item in &mut cat.items {
+                enabled |= apply(item, picked, index);
+            }
+            cat.enabled = enabled;
+            enabled
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apocalypse_library_types as sdk;
+
+    use super::*;
+
+    fn doc(id: &str, size: u64, priority: u8, requires: &[&str]) -> sdk::Document {
+        let mut builder = sdk::Document::builder(id, format!("https://example.com/{id}"))
+            .id(id)
+            .size(size)
+            .priority(priority)
+            .enabled(false);
+        for id in requires {
+            builder = builder.require(*id);
+        }
+        builder.build()
+    }
+
+    /// Fits `items` to `capacity`, returning the ids of the enabled Documents along with the `Fit`
+    fn fit_items(items: Vec<sdk::LibraryItem>, capacity: u64) -> (Vec<String>, Fit) {
+        let mut root: Category = sdk::Category::builder("Root").items(items).build().into();
+        let fit = fit(&mut root, capacity);
+        let mut enabled = Vec::new();
+        root.for_each_document_mut(&mut |doc| {
+            if doc.enabled {
+                enabled.push(doc.id().to_string());
+            }
+        });
+        enabled.sort_unstable();
+        (enabled, fit)
+    }
+
+    #[test]
+    fn parses_capacities() {
+        assert_eq!(parse_capacity("256GB"), Some(256_000_000_000));
+        assert_eq!(parse_capacity("1 TB"), Some(1_000_000_000_000));
+        assert_eq!(parse_capacity("1.5k"), Some(1500));
+        assert_eq!(parse_capacity("2GiB"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_capacity("100"), Some(100));
+        assert_eq!(parse_capacity("big"), None);
+        assert_eq!(parse_capacity("10 bytes"), None);
+    }
+
+    #[test]
+    fn requirements_are_pulled_in() {
+        let items = vec![
+            doc("app", 10, 9, &["lib"]).into(),
+            doc("lib", 5, 1, &[]).into(),
+        ];
+        let (enabled, fit) = fit_items(items, 20);
+        assert_eq!(enabled, ["app", "lib"]);
+        assert_eq!((fit.picked, fit.required, fit.used), (2, 1, 15));
+    }
+
+    #[test]
+    fn requirement_over_budget_leaves_the_item_out() {
+        let items = vec![
+            doc("app", 10, 9, &["lib"]).into(),
+            doc("lib", 15, 1, &[]).into(),
+            doc("other", 8, 5, &[]).into(),
+        ];
+        let (enabled, fit) = fit_items(items, 20);
+        assert_eq!(enabled, ["other"]);
+        assert_eq!(fit.used, 8);
+    }
+
+    #[test]
+    fn only_one_item_is_picked_in_a_single_selection_category() {
+        let wikipedia = sdk::Category::builder("Wikipedia")
+            .single_selection(true)
+            .item(doc("maxi", 100, 9, &[]))
+            .item(doc("mini", 10, 9, &[]))
+            .build();
+        let (enabled, _) = fit_items(vec![wikipedia.clone().into()], 1000);
+        assert_eq!(enabled, ["maxi"]);
+
+        // A requirement takes the choice, so the larger alternative conflicts with it
+        let items = vec![wikipedia.into(), doc("reader", 1, 10, &["mini"]).into()];
+        let (enabled, _) = fit_items(items, 1000);
+        assert_eq!(enabled, ["mini", "reader"]);
+    }
+
+    #[test]
+    fn items_that_cant_be_sized_are_left_out() {
+        let items = vec![
+            doc("unsized", 0, 9, &[]).into(),
+            doc("needs-unsized", 10, 9, &["unsized"]).into(),
+            doc("needs-missing", 10, 9, &["missing"]).into(),
+            doc("needs-needs", 10, 9, &["needs-missing"]).into(),
+            doc("plain", 10, 1, &[]).into(),
+        ];
+        let (enabled, fit) = fit_items(items, 1000);
+        assert_eq!(enabled, ["plain"]);
+        assert_eq!(fit.unknown, ["unsized"]);
+        assert_eq!(fit.unmet, ["needs-unsized", "needs-missing", "needs-needs"]);
+    }
+
+    #[test]
+    fn the_more_valuable_pass_is_kept() {
+        // Smallest first fits both small items where largest first only fits the big one
+        let items = vec![
+            doc("big", 6, 5, &[]).into(),
+            doc("small-a", 5, 5, &[]).into(),
+            doc("small-b", 5, 5, &[]).into(),
+        ];
+        let (enabled, fit) = fit_items(items, 10);
+        assert_eq!(enabled, ["small-a", "small-b"]);
+        assert_eq!(fit.used, 10);
+
+        // Largest first fills the space where smallest first leaves most of it empty
+        let items = vec![
+            doc("big", 10, 5, &[]).into(),
+            doc("small-a", 3, 5, &[]).into(),
+            doc("small-b", 3, 5, &[]).into(),
+        ];
+        let (enabled, fit) = fit_items(items, 10);
+        assert_eq!(enabled, ["big"]);
+        assert_eq!(fit.used, 10);
+    }
+}
@@ -0,0 +1,30 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Returns the folder that cached data is stored in
+pub fn dir() -> Result<PathBuf> {
+    dirs::cache_dir()
+        .map(|dir| dir.join("apocalypse_library"))
+        .ok_or_else(|| anyhow!("Could not find a cache directory for this system"))
+}
+
+/// Loads the cached data stored as `name`, if it exists and can be read
+pub fn load<T: DeserializeOwned>(name: &str) -> Option<T> {
+    let path = dir().ok()?.join(name);
+    let str = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&str).ok()
+}
+
+/// Stores `data` in the cache as `name`, replacing anything that was there before
+pub fn save<T: Serialize>(name: &str, data: &T) -> Result<()> {
+    let dir = dir()?;
+    fs::create_dir_all(&dir)?;
+    // Write to a temporary file first so that a crash can't leave a half written cache behind
+    let path = dir.join(name);
+    let temp = dir.join(format!("{name}.tmp"));
+    fs::write(&temp, serde_json::to_string(data)?)?;
+    fs::rename(temp, path)?;
+    Ok(())
+}
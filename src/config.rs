@@ -0,0 +1,274 @@
+use std::{
+    collections::HashMap,
+    env::consts,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{cache, profile::NewItems};
+
+/// The version of `PluginInput`, bumped whenever it changes in a way plugins need to know about
+pub const INPUT_VERSION: u32 = 1;
+
+/// The prefix of every environment variable passed to plugins
+const ENV_PREFIX: &str = "APOCALYPSE_LIBRARY";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The platform that the library is being downloaded for, which may not be the one this is
+/// running on. Uses the same names as Rust's `std::env::consts`, such as `linux` and `x86_64`
+pub struct Platform {
+    /// The operating system, such as `linux`, `windows` or `macos`
+    pub os: String,
+    /// The CPU architecture, such as `x86_64` or `aarch64`
+    pub arch: String,
+}
+
+impl Default for Platform {
+    fn default() -> Self {
+        Self {
+            os: consts::OS.into(),
+            arch: consts::ARCH.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// A catalog fetched from a url rather than loaded from a local plugin
+pub struct RemoteCatalog {
+    /// Where to get the catalog from, either an `http(s)://` or a `file://` url
+    pub url: String,
+    /// A minisign public key, either the base64 key or the whole contents of the `.pub` file. If
+    /// set the catalog is only loaded if its detached signature is valid
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// Where to get the detached signature from, defaults to `url` with `.minisig` appended
+    #[serde(default)]
+    pub signature_url: Option<String>,
+}
+
+impl RemoteCatalog {
+    /// Creates an unsigned catalog at `url`
+    pub const fn new(url: String) -> Self {
+        Self {
+            url,
+            public_key: None,
+            signature_url: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// What a WASM plugin is allowed to do. Nothing beyond running and writing its output is allowed
+/// unless it is granted here
+pub struct Grants {
+    /// Urls the plugin may fetch through the host HTTP API, along with everything below them. A
+    /// grant of `https://download.kiwix.org/zim/` allows that scheme, host and port, and any path
+    /// whose segments start with `zim`
+    pub http: Vec<String>,
+    /// Folders the plugin may read, these appear at the same path inside the plugin
+    pub read_dirs: Vec<PathBuf>,
+    /// Gives the plugin its own cache folder to read and write, at `/cache` inside the plugin
+    pub cache_dir: bool,
+    /// The most memory the plugin may use, in MiB
+    pub memory_mb: u64,
+    /// How much CPU time the plugin may use, in millions of fuel. Each WASM instruction uses
+    /// roughly one fuel
+    pub fuel_millions: u64,
+    /// The largest HTTP response the plugin may fetch, in MiB
+    pub max_response_mb: u64,
+}
+
+impl Default for Grants {
+    fn default() -> Self {
+        Self {
+            http: Vec::new(),
+            read_dirs: Vec::new(),
+            cache_dir: false,
+            memory_mb: 256,
+            fuel_millions: 50_000,
+            max_response_mb: 64,
+        }
+    }
+}
+
+impl Grants {
+    /// Returns the most memory the plugin may use in bytes, `None` if it doesn't fit in a `u64`
+    pub const fn memory_bytes(&self) -> Option<u64> {
+        self.memory_mb.checked_mul(1024 * 1024)
+    }
+
+    /// Returns the largest HTTP response the plugin may fetch in bytes, `None` if it doesn't fit
+    /// in a `u64`
+    pub const fn max_response_bytes(&self) -> Option<u64> {
+        self.max_response_mb.checked_mul(1024 * 1024)
+    }
+
+    /// Returns an error if any of the grants can't be used
+    fn validate(&self) -> Result<(), String> {
+        if self.memory_bytes().is_none() {
+            return Err(format!("memory_mb of {} is too large", self.memory_mb));
+        }
+        if self.max_response_bytes().is_none() {
+            return Err(format!(
+                "max_response_mb of {} is too large",
+                self.max_response_mb
+            ));
+        }
+        for url in &self.http {
+            let parsed =
+                Url::parse(url).map_err(|err| format!("http url {url} is invalid: {err}"))?;
+            if !matches!(parsed.scheme(), "http" | "https") || !parsed.has_host() {
+                return Err(format!("http url {url} is not an http(s) url with a host"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the entry of `map` for the plugin called `name`. Entries can be keyed by the full file
+/// name or without the extension, so that `kiwix` also matches `kiwix.exe`
+fn for_plugin<'a, T>(map: &'a HashMap<String, T>, name: &str) -> Option<&'a T> {
+    let stem = Path::new(name)
+        .file_stem()
+        .map_or_else(|| name.into(), |stem| stem.to_string_lossy());
+    map.get(name).or_else(|| map.get(stem.as_ref()))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// User configuration, read from `config.toml`
+pub struct Config {
+    /// The platform that plugins should pick downloads for, defaults to this system
+    pub platform: Platform,
+    /// A table of settings for each plugin, by plugin name. These are passed to the plugin as is
+    pub plugins: HashMap<String, Map<String, Value>>,
+    /// Catalogs to fetch from urls, alongside the local plugins
+    pub catalogs: Vec<RemoteCatalog>,
+    /// What each WASM plugin is allowed to do, by plugin name
+    pub grants: HashMap<String, Grants>,
+    /// If items that are new since a profile was saved are downloaded, `enabled` or `disabled`
+    pub new_items: NewItems,
+}
+
+impl Config {
+    /// Returns where the config file is read from when no other path is provided
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("apocalypse_library").join("config.toml"))
+    }
+
+    /// Loads the config file at `path`, or from `default_path` if `None`. A missing default
+    /// config file just means the defaults are used, but a missing `path` is an error
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match Self::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+        if !required && !path.exists() {
+            return Ok(Self::default());
+        }
+        let str = fs::read_to_string(&path)
+            .map_err(|err| anyhow!("Failed to read config {}: {err}", path.display()))?;
+        let config: Self = toml::from_str(&str)
+            .map_err(|err| anyhow!("Invalid config {}: {err}", path.display()))?;
+        for (name, grants) in &config.grants {
+            grants.validate().map_err(|err| {
+                anyhow!(
+                    "Invalid config {}: grants for {name}: {err}",
+                    path.display()
+                )
+            })?;
+        }
+        Ok(config)
+    }
+
+    /// Returns the settings for the plugin called `name`
+    pub fn settings(&self, name: &str) -> Map<String, Value> {
+        for_plugin(&self.plugins, name).cloned().unwrap_or_default()
+    }
+
+    /// Returns what the WASM plugin called `name` is allowed to do
+    pub fn grants(&self, name: &str) -> Grants {
+        for_plugin(&self.grants, name).cloned().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+/// Everything passed to an executable plugin when it is run. This is written to the plugin's
+/// stdin as JSON, and also passed in environment variables for plugins that would rather not
+/// parse JSON, see `env_vars`
+pub struct PluginInput {
+    /// The version of this format, see `INPUT_VERSION`
+    pub version: u32,
+    /// The name of the plugin being run
+    pub name: String,
+    /// The settings the user has configured for this plugin
+    pub settings: Map<String, Value>,
+    /// The platform that downloads should be picked for
+    pub platform: Platform,
+    /// A folder the plugin can keep its own cached data in between runs
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl PluginInput {
+    /// Builds the input for the plugin called `name` from the user's `config`
+    pub fn new(name: &str, config: &Config) -> Self {
+        let cache_dir = cache::dir().ok().map(|dir| dir.join("plugins").join(name));
+        Self {
+            version: INPUT_VERSION,
+            name: name.to_string(),
+            settings: config.settings(name),
+            platform: config.platform.clone(),
+            cache_dir,
+        }
+    }
+
+    /// Returns the environment variables to run the plugin with. The whole input is in
+    /// `APOCALYPSE_LIBRARY_INPUT`, with each part also in its own variable. Each setting is in
+    /// `APOCALYPSE_LIBRARY_SETTING_<NAME>`, strings as they are and anything else as JSON
+    pub fn env_vars(&self) -> Vec<(String, String)> {
+        let mut vars = vec![
+            (
+                format!("{ENV_PREFIX}_INPUT"),
+                serde_json::to_string(self).unwrap_or_default(),
+            ),
+            (format!("{ENV_PREFIX}_VERSION"), self.version.to_string()),
+            (format!("{ENV_PREFIX}_OS"), self.platform.os.clone()),
+            (format!("{ENV_PREFIX}_ARCH"), self.platform.arch.clone()),
+        ];
+        if let Some(cache_dir) = &self.cache_dir {
+            vars.push((
+                format!("{ENV_PREFIX}_CACHE_DIR"),
+                cache_dir.to_string_lossy().to_string(),
+            ));
+        }
+        vars.extend(self.settings.iter().map(|(key, value)| {
+            let key = key
+                .chars()
+                .map(|char| {
+                    if char.is_ascii_alphanumeric() {
+                        char.to_ascii_uppercase()
+                    } else {
+                        '_'
+                    }
+                })
+                .collect::<String>();
+            let value = match value {
+                Value::String(str) => str.clone(),
+                value => value.to_string(),
+            };
+            (format!("{ENV_PREFIX}_SETTING_{key}"), value)
+        }));
+        vars
+    }
+}
@@ -3,15 +3,21 @@ use std::{
     io::Write,
     path::Path,
     process::{Command, Stdio},
+    sync::LazyLock,
 };
 
 use anyhow::{anyhow, Result};
+use chrono::{Local, NaiveDateTime};
 use futures_util::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
-use once_cell::sync::Lazy;
 use tokio::runtime::Runtime;
 
-use crate::types::LibraryItem;
+use crate::{
+    filetype::{Expected, MAGIC_BYTES_NEEDED},
+    filter::RsyncFilters,
+    types::{Document, LibraryItem, SizeStats},
+    zsync,
+};
 
 pub fn setup_folder(path_str: &str) -> Result<()> {
     let path = Path::new(path_str);
@@ -29,19 +35,36 @@ pub fn setup_folder(path_str: &str) -> Result<()> {
 }
 
 // Code sourced from https://gist.github.com/giuliano-oliveira/4d11d6b3bb003dba3a1b53f43d81b30d
-pub async fn get_file(client: &reqwest::Client, url: &str, path: &str) -> Result<(), String> {
+/// Downloads the file at `url` to `path`. The data is written to a temporary file next to `path`
+/// that only gets moved into place once the download has completed and passed sanity checks
+/// against the `expected` file type
+pub async fn get_file(
+    client: &reqwest::Client,
+    url: &str,
+    path: &str,
+    expected: &Expected,
+) -> Result<(), String> {
     // Reqwest setup
     let res = client
         .get(url)
         .send()
         .await
         .or(Err(format!("Failed to GET from '{}'", &url)))?;
+    if !res.status().is_success() {
+        return Err(format!("GET from '{url}' returned {}", res.status()));
+    }
+    let content_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    expected.check_content_type(content_type)?;
     let total_size = res
         .content_length()
-        .ok_or(format!("Failed to get content length from '{}'", &url))?;
+        .ok_or_else(|| format!("Failed to get content length from '{}'", &url))?;
 
     // Indicatif setup
     let pb = ProgressBar::new(total_size);
+    #[allow(clippy::literal_string_with_formatting_args)]
     pb.set_style(ProgressStyle::default_bar()
         .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
         .unwrap()
@@ -49,25 +72,78 @@ pub async fn get_file(client: &reqwest::Client, url: &str, path: &str) -> Result
     pb.set_message(format!("Downloading {url}"));
 
     // download chunks
-    let mut file = File::create(path).or(Err(format!("Failed to create file '{path}'")))?;
+    let temp_path = temp_path(path);
+    let result = write_temp_file(res, &temp_path, total_size, &pb)
+        .await
+        .and_then(|(downloaded, head)| {
+            // Make sure we actually got the file we wanted before putting it in place, as
+            // otherwise it would be treated as complete and never downloaded again
+            if downloaded < total_size {
+                return Err(format!(
+                    "Download of '{url}' ended early, got {downloaded} of {total_size} bytes"
+                ));
+            }
+            expected.check_magic(&head, downloaded)
+        })
+        .and_then(|()| {
+            fs::rename(&temp_path, path)
+                .map_err(|_| format!("Failed to move '{temp_path}' to '{path}'"))
+        });
+    if let Err(err) = result {
+        // Partial files are never resumed, so don't leave them lying around
+        let _ = fs::remove_file(&temp_path);
+        pb.abandon_with_message(format!("Failed to download {url}: {err}"));
+        return Err(err);
+    }
+
+    pb.finish_with_message(format!("Downloaded {url} to {path}"));
+    Ok(())
+}
+
+/// Writes the body of `res` to the file at `temp_path`, updating the progress bar as it goes.
+/// Returns how many bytes were written, along with the leading bytes for checking the file type
+async fn write_temp_file(
+    res: reqwest::Response,
+    temp_path: &str,
+    total_size: u64,
+    pb: &ProgressBar,
+) -> Result<(u64, Vec<u8>), String> {
+    let mut file =
+        File::create(temp_path).or(Err(format!("Failed to create file '{temp_path}'")))?;
     let mut downloaded: u64 = 0;
+    let mut head: Vec<u8> = Vec::new();
     let mut stream = res.bytes_stream();
 
     while let Some(item) = stream.next().await {
         let chunk = item.or(Err("Error while downloading file"))?;
         file.write_all(&chunk)
             .or(Err("Error while writing to file"))?;
+        if head.len() < MAGIC_BYTES_NEEDED {
+            let needed = usize::min(MAGIC_BYTES_NEEDED - head.len(), chunk.len());
+            head.extend_from_slice(&chunk[..needed]);
+        }
         let new = std::cmp::min(downloaded + (chunk.len() as u64), total_size);
         downloaded = new;
         pb.set_position(new);
     }
+    Ok((downloaded, head))
+}
 
-    pb.finish_with_message(format!("Downloaded {url} to {path}"));
-    Ok(())
+/// Returns the path that a file is downloaded to before it has been completed and checked
+pub fn temp_path(path: &str) -> String {
+    format!("{path}.part")
 }
 
-pub fn handle_download_file(url: &str, path_str: &str, overwrite: bool) -> Result<()> {
-    static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+/// Downloads the file at `url` to `path_str`. If a `zsync_url` is provided and a previous version
+/// of the file exists locally, only the parts of the file that have changed are downloaded
+pub fn handle_download_file(
+    url: &str,
+    path_str: &str,
+    overwrite: bool,
+    mime_type: Option<&str>,
+    zsync_url: Option<&str>,
+) -> Result<()> {
+    static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
         reqwest::ClientBuilder::new()
             .user_agent("Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/117.0")
             .use_rustls_tls()
@@ -75,29 +151,85 @@ pub fn handle_download_file(url: &str, path_str: &str, overwrite: bool) -> Resul
             .unwrap()
     });
 
-    static RT: Lazy<Runtime> = Lazy::new(|| Runtime::new().unwrap());
+    static RT: LazyLock<Runtime> = LazyLock::new(|| Runtime::new().unwrap());
 
     let path = Path::new(path_str);
     if path.exists() {
         if path.is_dir() {
-            panic!("Cant download file {path_str}, as a folder exists in its place");
-        } else if !overwrite {
-            // If we're not overwriting, then we assume this file is done
+            return Err(anyhow!(
+                "Cant download file {path_str}, as a folder exists in its place"
+            ));
+        } else if !overwrite && zsync_url.is_none() {
+            // If we're not overwriting, then we assume this file is done, as files are only moved
+            // into place after they have been completed
             return Ok(());
-        } else {
-            // We're overwriting an existing file, so we need to delete the old one first
-            fs::remove_file(path)?;
+        }
+        // Otherwise the old file is replaced once the new one is complete. With a zsync control
+        // file it seeds the new version, and is kept as it is if it's already current
+    }
+
+    let expected = Expected::new(path_str, mime_type);
+    if let Some((zsync_url, seed)) = zsync_url.zip(zsync::find_seed(path)) {
+        let temp_path = temp_path(path_str);
+        match RT.block_on(zsync::get_file(
+            &CLIENT, zsync_url, &seed, path_str, &temp_path, &expected,
+        )) {
+            Ok(()) => return Ok(()),
+            Err(err) if path.exists() && !overwrite => {
+                // The existing file is complete, just maybe not the newest, so keep it rather
+                // than downloading the whole file again on every run
+                eprintln!("Failed to update {path_str} with zsync, keeping it as it is: {err}");
+                return Ok(());
+            }
+            // Fall back to downloading the whole file
+            Err(err) => eprintln!("Failed to update {path_str} with zsync: {err}"),
         }
     }
 
-    RT.block_on(get_file(&CLIENT, url, path_str))
+    RT.block_on(get_file(&CLIENT, url, path_str, &expected))
         .err()
         .map_or_else(|| Ok(()), |err| Err(anyhow!("{err}")))
 }
 
-fn handle_download_rsync(url: &str, path_str: &str) -> Result<()> {
+/// The format used for the names of rsync snapshot folders
+const SNAPSHOT_FORMAT: &str = "%Y-%m-%d_%H%M%S";
+/// The suffix of a snapshot folder that hasn't finished syncing yet
+const SNAPSHOT_INCOMPLETE: &str = ".incomplete";
+/// The name of the symlink pointing to the newest complete snapshot
+const SNAPSHOT_CURRENT: &str = "current";
+
+/// Settings that control how items are downloaded
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Use HTTP rather than rsync for items that support both
+    pub prefer_http: bool,
+    /// If set, rsync items are synced into dated snapshot folders and this many are kept
+    pub rsync_snapshots: Option<usize>,
+}
+
+fn handle_download_rsync(
+    url: &str,
+    path_str: &str,
+    filters: Option<&RsyncFilters>,
+    options: &Options,
+) -> Result<()> {
     println!("Starting Download: {url}");
 
+    let url = rsync_url(url);
+    let filter_args = filters.map(RsyncFilters::rsync_args).unwrap_or_default();
+    if let Some(keep) = options.rsync_snapshots {
+        return handle_download_rsync_snapshot(&url, path_str, &filter_args, keep);
+    }
+
+    run_rsync(&url, path_str, &filter_args)?;
+
+    println!("Completed Download: {url}");
+
+    Ok(())
+}
+
+/// Converts the url of a `Document` to the rsync url to sync it from
+fn rsync_url(url: &str) -> String {
     let mut url = url.to_string();
 
     if url.starts_with("http://") {
@@ -108,27 +240,253 @@ fn handle_download_rsync(url: &str, path_str: &str) -> Result<()> {
         url = format!("rsync://{url}");
     }
 
-    Command::new("rsync")
-        .args([
-            "-rlptH",
-            "--safe-links",
-            "--delete-delay",
-            "--delay-updates",
-            "--no-motd",
-            "--progress",
-            "-h",
-            &url,
-            path_str,
-        ])
+    url
+}
+
+/// The arguments used for every rsync run
+const RSYNC_ARGS: [&str; 5] = [
+    "-rlptH",
+    "--safe-links",
+    "--delete-delay",
+    "--delay-updates",
+    "--no-motd",
+];
+
+/// Runs rsync to sync `url` into `path_str`, with any `extra_args`
+fn run_rsync(url: &str, path_str: &str, extra_args: &[String]) -> Result<()> {
+    let status = Command::new("rsync")
+        .args(RSYNC_ARGS)
+        .args(["--progress", "-h"])
+        .args(extra_args)
+        .args([url, path_str])
         .stdin(Stdio::inherit())
         .stdout(Stdio::inherit())
-        .output()?;
+        .status()?;
 
-    println!("Completed Download: {url}");
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("rsync of {url} failed with {status}"))
+    }
+}
+
+/// Returns the names of the complete snapshots in `path`, oldest first
+fn list_snapshots(path: &Path) -> Result<Vec<String>> {
+    let mut snapshots: Vec<String> = path
+        .read_dir()?
+        .filter_map(std::result::Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| NaiveDateTime::parse_from_str(name, SNAPSHOT_FORMAT).is_ok())
+        .collect();
+    snapshots.sort_unstable();
+    Ok(snapshots)
+}
+
+/// Syncs `url` into a new dated snapshot folder inside `path_str`. Files that haven't changed
+/// since the previous snapshot are hard linked to it with `--link-dest` so they take no extra
+/// space. Once the sync has completed the `current` symlink is pointed at the new snapshot and
+/// all but the newest `keep` snapshots are removed
+fn handle_download_rsync_snapshot(
+    url: &str,
+    path_str: &str,
+    filter_args: &[String],
+    keep: usize,
+) -> Result<()> {
+    setup_folder(path_str)?;
+    let path = Path::new(path_str);
+    let snapshots = list_snapshots(path)?;
+
+    // Resume an interrupted snapshot if there is one, rather than starting over
+    let name = Local::now().format(SNAPSHOT_FORMAT).to_string();
+    let incomplete = path
+        .read_dir()?
+        .filter_map(std::result::Result::ok)
+        .map(|entry| entry.path())
+        .find(|entry| {
+            entry.is_dir()
+                && entry
+                    .to_str()
+                    .is_some_and(|entry| entry.ends_with(SNAPSHOT_INCOMPLETE))
+        })
+        .unwrap_or_else(|| path.join(format!("{name}{SNAPSHOT_INCOMPLETE}")));
+
+    let mut extra_args = filter_args.to_vec();
+    if let Some(previous) = snapshots.last() {
+        // rsync resolves a relative --link-dest against the destination, so make it absolute
+        let previous = path.join(previous).canonicalize()?;
+        extra_args.push(format!("--link-dest={}", previous.display()));
+    }
+    // rsync copies the contents of a folder rather than the folder itself with a trailing slash
+    let destination = format!("{}/", incomplete.display());
+    run_rsync(url, &destination, &extra_args)?;
+
+    let snapshot = path.join(&name);
+    fs::rename(&incomplete, &snapshot)?;
+    update_current_snapshot(path, &name)?;
+    println!("Completed Download: {url} as snapshot {name}");
+
+    // Apply the retention policy, always keeping the snapshot we just made
+    let snapshots = list_snapshots(path)?;
+    let remove = snapshots.len().saturating_sub(usize::max(keep, 1));
+    for old in &snapshots[..remove] {
+        println!("Removing old snapshot: {path_str}/{old}");
+        fs::remove_dir_all(path.join(old))?;
+    }
 
     Ok(())
 }
 
+/// Points the `current` symlink in `path` at the snapshot called `name`
+#[cfg(unix)]
+fn update_current_snapshot(path: &Path, name: &str) -> Result<()> {
+    // Create the new link next to the old one and rename it over the top, so that there is never
+    // a moment where `current` doesn't exist
+    let temp = path.join(format!("{SNAPSHOT_CURRENT}.new"));
+    if temp.symlink_metadata().is_ok() {
+        fs::remove_file(&temp)?;
+    }
+    std::os::unix::fs::symlink(name, &temp)?;
+    fs::rename(temp, path.join(SNAPSHOT_CURRENT))?;
+    Ok(())
+}
+
+/// Points the `current` symlink in `path` at the snapshot called `name`
+#[cfg(not(unix))]
+fn update_current_snapshot(_path: &Path, _name: &str) -> Result<()> {
+    // We can't run rsync outside of unix systems anyway
+    Ok(())
+}
+
+/// Returns if the `Document` is downloaded with rsync rather than HTTP
+pub fn uses_rsync(doc: &Document, options: &Options) -> bool {
+    match doc.download_type() {
+        crate::types::DownloadType::Http => false,
+        crate::types::DownloadType::Rsync => true,
+        crate::types::DownloadType::Either => {
+            !(crate::IS_WINDOWS || !*crate::HAS_RSYNC || options.prefer_http)
+        }
+    }
+}
+
+/// Returns where the `Document` is downloaded to, inside the folder at `path`
+pub fn destination(path: &str, doc: &Document, options: &Options) -> String {
+    if !uses_rsync(doc, options) {
+        let file_name = doc.url().split('/').next_back().unwrap_or_default();
+        return format!("{path}/{file_name}");
+    }
+    if options.rsync_snapshots.is_some() {
+        format!("{path}/{}/{SNAPSHOT_CURRENT}", doc.name())
+    } else {
+        format!("{path}/{}", doc.name())
+    }
+}
+
+/// A rsync `Document` to gather accurate sizes for
+#[derive(Debug, Clone)]
+pub struct RsyncJob {
+    /// The `size_key` of the `Document`, used to match the results back up with it
+    pub key: String,
+    /// The url of the `Document`
+    url: String,
+    /// The local path that the `Document` is synced to
+    path: String,
+    /// The filter arguments for the `Document`
+    filter_args: Vec<String>,
+}
+
+/// Returns the key that the rsync dry run sizes of `doc` are cached under. This includes the
+/// filters, as the sizes are only right for the filters they were measured with
+pub fn size_key(doc: &Document) -> String {
+    let filter_args = doc.filters().map(RsyncFilters::rsync_args);
+    std::iter::once(doc.url().to_string())
+        .chain(filter_args.into_iter().flatten())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Collects a `RsyncJob` for every enabled `Document` in `item` that would be downloaded with rsync
+/// into the folder at `path`
+pub fn collect_rsync_jobs(
+    path: &str,
+    item: &LibraryItem,
+    options: &Options,
+    jobs: &mut Vec<RsyncJob>,
+) {
+    if !item.enabled() {
+        return;
+    }
+    match item {
+        LibraryItem::Document(doc) => {
+            if uses_rsync(doc, options) && doc.can_download() {
+                let mut path = format!("{path}/{}", doc.name());
+                if options.rsync_snapshots.is_some() {
+                    // Compare against the newest snapshot
+                    path = format!("{path}/{SNAPSHOT_CURRENT}/");
+                }
+                jobs.push(RsyncJob {
+                    key: size_key(doc),
+                    url: doc.url().to_string(),
+                    path,
+                    filter_args: doc
+                        .filters()
+                        .map(RsyncFilters::rsync_args)
+                        .unwrap_or_default(),
+                });
+            }
+        }
+        LibraryItem::Category(cat) => {
+            let path = format!("{path}/{}", cat.name());
+            cat.items
+                .iter()
+                .for_each(|item| collect_rsync_jobs(&path, item, options, jobs));
+        }
+    }
+}
+
+/// Runs a rsync dry run for the `job` to find the total size of the files on the server, and how
+/// much of that still needs to be transferred to bring the local copy up to date
+pub fn rsync_stats(job: &RsyncJob) -> Result<SizeStats> {
+    let url = rsync_url(&job.url);
+    let output = Command::new("rsync")
+        .args(RSYNC_ARGS)
+        .args(["--dry-run", "--stats", "--no-human-readable"])
+        .args(&job.filter_args)
+        .args([url.as_str(), job.path.as_str()])
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "rsync dry run of {url} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stat = |name: &str| -> Result<u64> {
+        let line = stdout
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(name))
+            .ok_or_else(|| anyhow!("rsync output for {url} is missing '{name}'"))?;
+        // Older versions of rsync group the digits even without human readable output
+        let digits: String = line
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .filter(char::is_ascii_digit)
+            .collect();
+        Ok(digits.parse()?)
+    };
+
+    Ok(SizeStats {
+        total: stat("Total file size:")?,
+        remaining: stat("Total transferred file size:")?,
+        checked: Local::now().timestamp(),
+    })
+}
+
 pub fn check_for_rsync() -> bool {
     Command::new("which")
         .arg("rsync")
@@ -138,35 +496,60 @@ pub fn check_for_rsync() -> bool {
         .success()
 }
 
-pub fn get_item(path: &str, item: &LibraryItem, prefer_http: bool) {
+/// Downloads the `Document` into the folder at `path` with HTTP, unless its filters exclude it
+fn get_document_http(path: &str, doc: &Document) -> Result<()> {
+    let file_name = doc.url().split('/').next_back().unwrap();
+    if doc
+        .filters()
+        .is_some_and(|filters| !filters.allows(file_name, doc.size()))
+    {
+        println!(
+            "Skipping {file_name}, as it is excluded by the filters for {}",
+            doc.name()
+        );
+        return Ok(());
+    }
+    let path = format!("{path}/{file_name}");
+    handle_download_file(doc.url(), &path, false, doc.mime_type(), doc.zsync_url())
+}
+
+/// Downloads the provided `item` and all of its enabled contents to `path`. Anything that fails to
+/// download is reported and added to `failed` so that it can be retried later
+pub fn get_item(path: &str, item: &LibraryItem, options: &Options, failed: &mut Vec<String>) {
     if !item.enabled() {
         return;
     }
     match item {
-        LibraryItem::Document(doc) => match doc.download_type() {
-            crate::types::DownloadType::Http => {
-                let path = format!("{path}/{}", doc.url().split('/').last().unwrap());
-                handle_download_file(doc.url(), &path, false)
-            }
-            crate::types::DownloadType::Rsync => {
-                handle_download_rsync(doc.url(), &format!("{path}/{}", doc.name()))
-            }
-            crate::types::DownloadType::Either => {
-                if crate::IS_WINDOWS || !*crate::HAS_RSYNC || prefer_http {
-                    let path = format!("{path}/{}", doc.url().split('/').last().unwrap());
-                    handle_download_file(doc.url(), &path, false)
-                } else {
-                    handle_download_rsync(doc.url(), &format!("{path}/{}", doc.name()))
+        LibraryItem::Document(doc) => {
+            let rsync_path = format!("{path}/{}", doc.name());
+            let result = match doc.download_type() {
+                crate::types::DownloadType::Http => get_document_http(path, doc),
+                crate::types::DownloadType::Rsync => {
+                    handle_download_rsync(doc.url(), &rsync_path, doc.filters(), options)
+                }
+                crate::types::DownloadType::Either => {
+                    if crate::IS_WINDOWS || !*crate::HAS_RSYNC || options.prefer_http {
+                        get_document_http(path, doc)
+                    } else {
+                        handle_download_rsync(doc.url(), &rsync_path, doc.filters(), options)
+                    }
                 }
+            };
+            if let Err(err) = result {
+                eprintln!("Failed to download {}: {err}", doc.name());
+                failed.push(format!("{path}/{}", doc.name()));
             }
         }
-        .unwrap(),
         LibraryItem::Category(cat) => {
             let path = format!("{path}/{}", cat.name());
-            setup_folder(&path).unwrap();
+            if let Err(err) = setup_folder(&path) {
+                eprintln!("Failed to create folder {path}: {err}");
+                failed.push(path);
+                return;
+            }
             cat.items
                 .iter()
-                .for_each(|item| get_item(&path, item, prefer_http));
+                .for_each(|item| get_item(&path, item, options, failed));
         }
     }
 }
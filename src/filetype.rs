@@ -0,0 +1,216 @@
+use std::path::Path;
+
+/// Content types that servers commonly send for any binary file, these tell us nothing about the
+/// actual contents so they're always accepted
+const GENERIC_TYPES: [&str; 4] = [
+    "application/octet-stream",
+    "binary/octet-stream",
+    "application/x-download",
+    "application/force-download",
+];
+
+/// A known file type that we can sanity check a download against
+#[derive(Debug, Clone, Copy)]
+pub struct FileType {
+    /// File extensions that map to this type
+    extensions: &'static [&'static str],
+    /// MIME types that are acceptable for this type, beyond the generic binary ones
+    mime_types: &'static [&'static str],
+    /// Offset and bytes expected at the offset, if the format has a reliable signature
+    magic: Option<(usize, &'static [u8])>,
+}
+
+const KNOWN_TYPES: [FileType; 12] = [
+    FileType {
+        extensions: &["zim"],
+        mime_types: &["application/x-zim", "application/zim"],
+        magic: Some((0, b"ZIM\x04")),
+    },
+    FileType {
+        extensions: &["pdf"],
+        mime_types: &["application/pdf", "application/x-pdf"],
+        magic: Some((0, b"%PDF")),
+    },
+    FileType {
+        extensions: &["zip", "apk", "jar", "epub", "xapk"],
+        mime_types: &[
+            "application/zip",
+            "application/x-zip-compressed",
+            "application/vnd.android.package-archive",
+            "application/java-archive",
+            "application/epub+zip",
+        ],
+        magic: Some((0, b"PK\x03\x04")),
+    },
+    FileType {
+        extensions: &["gz", "tgz"],
+        mime_types: &[
+            "application/gzip",
+            "application/x-gzip",
+            "application/x-tar",
+        ],
+        magic: Some((0, b"\x1f\x8b")),
+    },
+    FileType {
+        extensions: &["xz", "txz"],
+        mime_types: &["application/x-xz", "application/x-tar"],
+        magic: Some((0, b"\xfd7zXZ\x00")),
+    },
+    FileType {
+        extensions: &["zst"],
+        mime_types: &["application/zstd", "application/x-zstd"],
+        magic: Some((0, b"\x28\xb5\x2f\xfd")),
+    },
+    FileType {
+        extensions: &["bz2"],
+        mime_types: &["application/x-bzip2", "application/x-bzip"],
+        magic: Some((0, b"BZh")),
+    },
+    FileType {
+        extensions: &["iso"],
+        mime_types: &["application/x-iso9660-image", "application/x-cd-image"],
+        magic: Some((0x8001, b"CD001")),
+    },
+    FileType {
+        extensions: &["pbf"],
+        mime_types: &[
+            "application/x-protobuf",
+            "application/vnd.openstreetmap.data+pbf",
+        ],
+        // The first BlobHeader of an OSM PBF file always has the type "OSMHeader"
+        magic: Some((6, b"OSMHeader")),
+    },
+    FileType {
+        extensions: &["img"],
+        mime_types: &["application/x-raw-disk-image"],
+        magic: None,
+    },
+    FileType {
+        extensions: &["png"],
+        mime_types: &["image/png"],
+        magic: Some((0, b"\x89PNG")),
+    },
+    FileType {
+        extensions: &["html", "htm"],
+        mime_types: &["text/html", "application/xhtml+xml"],
+        magic: None,
+    },
+];
+
+/// The number of leading bytes of a download that need to be kept to check every known signature
+pub const MAGIC_BYTES_NEEDED: usize = 0x8001 + 5;
+
+/// What we expect a download to be, built from a declared MIME type and/or the file extension
+#[derive(Debug, Clone, Default)]
+pub struct Expected {
+    /// MIME type declared by the plugin for this file
+    declared: Option<String>,
+    /// The known type matching the declared MIME type or file extension
+    known: Option<FileType>,
+}
+
+impl Expected {
+    /// Works out what a file should contain from the provided `path` and declared `mime_type`
+    pub fn new(path: &str, mime_type: Option<&str>) -> Self {
+        let declared = mime_type.map(|mime| essence(mime).to_ascii_lowercase());
+        let known = declared
+            .as_deref()
+            .and_then(|mime| {
+                KNOWN_TYPES
+                    .iter()
+                    .find(|known| known.mime_types.contains(&mime))
+            })
+            .or_else(|| {
+                let extension = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+                KNOWN_TYPES
+                    .iter()
+                    .find(|known| known.extensions.contains(&extension.as_str()))
+            })
+            .copied();
+        Self { declared, known }
+    }
+
+    /// Returns if we're expecting an HTML or plain text page rather than some binary file
+    fn expects_text(&self) -> bool {
+        self.declared
+            .as_deref()
+            .is_some_and(|mime| mime.starts_with("text/"))
+            || self
+                .known
+                .is_some_and(|known| known.mime_types.contains(&"text/html"))
+    }
+
+    /// Checks the `Content-Type` header returned by the server against what we expect
+    pub fn check_content_type(&self, content_type: Option<&str>) -> Result<(), String> {
+        let Some(content_type) = content_type.map(|mime| essence(mime).to_ascii_lowercase()) else {
+            return Ok(());
+        };
+        let content_type = content_type.as_str();
+        if GENERIC_TYPES.contains(&content_type) {
+            return Ok(());
+        }
+        if self.expects_text() {
+            return Ok(());
+        }
+        if content_type.starts_with("text/") {
+            return Err(format!(
+                "Server returned '{content_type}' for what should be a binary file"
+            ));
+        }
+        if self.declared.as_deref() == Some(content_type)
+            || self
+                .known
+                .is_some_and(|known| known.mime_types.contains(&content_type))
+        {
+            return Ok(());
+        }
+        // Only fail on a mismatch with a type the plugin explicitly declared, servers are too
+        // inconsistent about the types they send for us to rely on our own table
+        self.declared.as_ref().map_or(Ok(()), |declared| {
+            Err(format!(
+                "Expected content type '{declared}' but server returned '{content_type}'"
+            ))
+        })
+    }
+
+    /// Checks the leading bytes of a completed download. `total_len` is the full size of the file,
+    /// which may be larger than `head` as we only keep the first `MAGIC_BYTES_NEEDED` bytes
+    pub fn check_magic(&self, head: &[u8], total_len: u64) -> Result<(), String> {
+        if !self.expects_text() && looks_like_html(head) {
+            return Err("Downloaded file is an HTML page rather than the expected file".into());
+        }
+        let Some((offset, magic)) = self.known.and_then(|known| known.magic) else {
+            return Ok(());
+        };
+        if total_len < (offset + magic.len()) as u64 {
+            return Err(format!(
+                "Downloaded file is too small to be a .{} file",
+                self.known.map_or("", |known| known.extensions[0])
+            ));
+        }
+        if head.get(offset..offset + magic.len()) == Some(magic) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Downloaded file does not look like a .{} file",
+                self.known.map_or("", |known| known.extensions[0])
+            ))
+        }
+    }
+}
+
+/// Strips any parameters such as `; charset=utf-8` from a MIME type
+fn essence(mime: &str) -> &str {
+    mime.split(';').next().unwrap_or_default().trim()
+}
+
+/// Returns if the provided bytes look like the start of an HTML document
+fn looks_like_html(head: &[u8]) -> bool {
+    let start = head.iter().position(|byte| !byte.is_ascii_whitespace());
+    let Some(start) = start else {
+        return false;
+    };
+    let head = &head[start..usize::min(head.len(), start + 15)];
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+    head.starts_with("<!doctype html") || head.starts_with("<html") || head.starts_with("<head")
+}
@@ -0,0 +1,151 @@
+use apocalypse_library_types as sdk;
+
+use sdk::FilterRule;
+
+#[derive(Debug, Default, Clone)]
+/// Rules limiting which files of a `Document` are downloaded. These are passed directly to rsync,
+/// and checked against the file name when falling back to HTTP
+pub struct RsyncFilters {
+    /// rsync filter rules, the first one that matches a file decides if it is downloaded
+    rules: Vec<FilterRule>,
+    /// Files larger than this many bytes are skipped
+    max_size: Option<u64>,
+    /// The size in bytes of the files that pass the filters according to the plugin, until a
+    /// rsync dry run measures it
+    size: Option<u64>,
+}
+
+impl From<sdk::RsyncFilters> for RsyncFilters {
+    fn from(filters: sdk::RsyncFilters) -> Self {
+        Self {
+            rules: filters.rules,
+            max_size: filters.max_size,
+            size: filters.size,
+        }
+    }
+}
+
+impl RsyncFilters {
+    /// Returns the size in bytes of the files that pass these filters according to the plugin,
+    /// if it gave one
+    pub const fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Returns the arguments to pass to rsync to apply these filters, with the rules in order
+    pub fn rsync_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = self
+            .rules
+            .iter()
+            .map(|rule| match rule {
+                FilterRule::Include(pattern) => format!("--include={pattern}"),
+                FilterRule::Exclude(pattern) => format!("--exclude={pattern}"),
+            })
+            .collect();
+        if self
+            .rules
+            .iter()
+            .any(|rule| matches!(rule, FilterRule::Include(_)))
+        {
+            // Includes are usually paired with `*/` to descend into every folder, don't keep the
+            // folders that end up with nothing in them
+            args.push("--prune-empty-dirs".into());
+        }
+        if let Some(max_size) = self.max_size {
+            args.push(format!("--max-size={max_size}"));
+        }
+        args
+    }
+
+    /// Returns if a file at the relative `path`, with the provided `size` in bytes, would be
+    /// downloaded. Follows rsync's rule that the first matching rule wins
+    pub fn allows(&self, path: &str, size: u64) -> bool {
+        if self.max_size.is_some_and(|max_size| size > max_size) {
+            return false;
+        }
+        self.rules
+            .iter()
+            .find_map(|rule| match rule {
+                FilterRule::Include(pattern) => pattern_matches(pattern, path).then_some(true),
+                FilterRule::Exclude(pattern) => pattern_matches(pattern, path).then_some(false),
+            })
+            .unwrap_or(true)
+    }
+}
+
+/// Returns if the rsync `pattern` matches the file at the relative `path`
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    // Patterns ending in a slash only match folders
+    if pattern.ends_with('/') {
+        return false;
+    }
+    let path = path.trim_start_matches('/');
+    if pattern.starts_with('/') {
+        glob_matches(&pattern.as_bytes()[1..], path.as_bytes())
+    } else if pattern.contains('/') || pattern.contains("**") {
+        // Like rsync, unanchored patterns with a slash or `**` are matched against the whole
+        // path, so they can match any trailing portion of it rather than only the file name
+        std::iter::once(path)
+            .chain(path.match_indices('/').map(|(i, _)| &path[i + 1..]))
+            .any(|tail| glob_matches(pattern.as_bytes(), tail.as_bytes()))
+    } else {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        glob_matches(pattern.as_bytes(), name.as_bytes())
+    }
+}
+
+/// Matches rsync style wildcards, `*` matches anything but a slash, `**` matches anything and `?`
+/// matches any single character other than a slash
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| glob_matches(rest, &text[i..])),
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|i| *i == 0 || text[i - 1] != b'/')
+            .any(|i| glob_matches(rest, &text[i..])),
+        [b'?', rest @ ..] => {
+            text.first().is_some_and(|char| *char != b'/') && glob_matches(rest, &text[1..])
+        }
+        [char, rest @ ..] => text.first() == Some(char) && glob_matches(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(rules: Vec<FilterRule>) -> RsyncFilters {
+        RsyncFilters {
+            rules,
+            ..RsyncFilters::default()
+        }
+    }
+
+    #[test]
+    fn double_star_matches_the_whole_path() {
+        assert!(pattern_matches("docs/**.pdf", "books/docs/a/b.pdf"));
+        assert!(pattern_matches("**/b.pdf", "a/b.pdf"));
+        assert!(pattern_matches("a**f", "a/b.pdf"));
+        assert!(!pattern_matches("a**f", "x/b.pdf"));
+    }
+
+    #[test]
+    fn plain_patterns_match_the_file_name() {
+        assert!(pattern_matches("*.pdf", "a/b.pdf"));
+        assert!(!pattern_matches("a*", "a/b.pdf"));
+        assert!(pattern_matches("/a/*.pdf", "a/b.pdf"));
+        assert!(!pattern_matches("/b.pdf", "a/b.pdf"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let filters = filters(vec![
+            FilterRule::Exclude("private/**".into()),
+            FilterRule::Include("**.pdf".into()),
+            FilterRule::Exclude("*".into()),
+        ]);
+        assert!(filters.allows("books/a.pdf", 1));
+        assert!(!filters.allows("private/books/a.pdf", 1));
+        assert!(!filters.allows("books/a.epub", 1));
+    }
+}
@@ -1,20 +1,32 @@
+mod budget;
+mod cache;
+mod config;
 mod download;
+mod filetype;
+mod filter;
 mod parsing;
+mod plugins;
+mod profile;
+mod remote;
+mod scrape;
 mod term;
+mod trust;
 mod types;
+mod wasm;
+mod zsync;
 
-use std::{env, path::Path};
+use std::{path::PathBuf, sync::LazyLock, time::Duration};
 
 use anyhow::{anyhow, Result};
 use clap::Parser;
-use once_cell::sync::Lazy;
 use ratatui::{backend::CrosstermBackend, Terminal};
 use term::{app::App, event::EventHandler, tui::Tui, update::update};
 
 static IS_WINDOWS: bool = cfg!(windows);
-static HAS_RSYNC: Lazy<bool> = Lazy::new(download::check_for_rsync);
+static HAS_RSYNC: LazyLock<bool> = LazyLock::new(download::check_for_rsync);
 
 #[derive(Parser, Debug)]
+#[allow(clippy::struct_excessive_bools)]
 #[command(author = "Cameron Barnes", version = "1.0", about = None, long_about = None)]
 struct Args {
     /// Path to output the downloaded content
@@ -22,37 +34,175 @@ struct Args {
     out_path: String,
     #[arg(short, long, default_value_t = false)]
     prefer_http: bool,
-    #[arg(short, long, default_value_t = String::from(""))]
-    plugin_path: String,
+    /// A folder to search for plugins, can be used more than once. These are searched before the
+    /// default folders, and take precedence over them when plugins share a name
+    #[arg(short = 'P', long)]
+    plugin_path: Vec<PathBuf>,
+    /// Only load static .json, .toml or .yaml catalog files, without running any plugins or
+    /// scraping any .source.toml sources
     #[arg(short, long, default_value_t = false)]
     direct_json: bool,
+    /// Sync rsync items into dated snapshot folders, keeping this many snapshots of each
+    #[arg(long)]
+    rsync_snapshots: Option<usize>,
+    /// How many seconds to let each plugin run for before stopping it
+    #[arg(long, default_value_t = 120)]
+    plugin_timeout: u64,
+    /// How many hours to reuse the cached output of a plugin for before running it again
+    #[arg(long, default_value_t = 24)]
+    cache_ttl: u64,
+    /// Run every plugin, ignoring any cached output
+    #[arg(long, default_value_t = false, conflicts_with = "offline")]
+    refresh: bool,
+    /// Only load plugin output from the cache, without running any plugins
+    #[arg(long, default_value_t = false)]
+    offline: bool,
+    /// A url of a catalog to load alongside the plugins, either `http(s)://` or `file://`. Can be
+    /// used more than once, signed catalogs can be added to the config file
+    #[arg(long)]
+    catalog_url: Vec<String>,
+    /// Run every executable plugin without asking, not only those that have been approved.
+    /// Executables run with full access to the system, unlike WASM plugins
+    #[arg(long, default_value_t = false)]
+    allow_executables: bool,
+    /// The config file to read plugin settings and the target platform from, defaults to
+    /// `apocalypse_library/config.toml` in the user's config folder
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// List every plugin found and if it is approved to run, then exit
+    #[arg(long, default_value_t = false)]
+    list_plugins: bool,
+    /// Approve the executable plugin with this file name to run as it is now, can be used more
+    /// than once. If the plugin changes it will need approving again
+    #[arg(long)]
+    approve: Vec<String>,
+    /// Remove the approval for the executable plugin with this file name, can be used more than
+    /// once
+    #[arg(long)]
+    revoke: Vec<String>,
+    /// The profile to enable items from, either the name of a saved profile or the path of a
+    /// profile file. Items that are new since it was saved use `new_items` from the config file
+    #[arg(long)]
+    profile: Option<String>,
+    /// Replace the selection with the most important items that fit on a drive of this size,
+    /// such as `256GB` or `1TB`, once the plugins have loaded. The result can still be changed
+    /// before downloading
+    #[arg(long)]
+    budget: Option<String>,
+    /// Print the JSON schema describing plugin output, then exit
+    #[arg(long, default_value_t = false)]
+    print_schema: bool,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+/// Approves and revokes the plugins passed on the command line, saving the changes to `trust`
+fn update_trust(args: &Args, plugin_dirs: &[PathBuf], trust: &mut trust::TrustStore) -> Result<()> {
+    if args.approve.is_empty() && args.revoke.is_empty() {
+        return Ok(());
+    }
+    for name in &args.revoke {
+        if !trust.revoke(name) {
+            return Err(anyhow!("Plugin {name} has not been approved"));
+        }
+        println!("Revoked approval for {name}");
+    }
+    let (found, _) = plugins::find(plugin_dirs, false);
+    for name in &args.approve {
+        let plugin = found
+            .iter()
+            .find(|plugin| plugin.name == *name && plugin.kind == plugins::Kind::Executable)
+            .ok_or_else(|| anyhow!("No executable plugin called {name} was found"))?;
+        let sha256 = trust::hash_file(&plugin.path)?;
+        println!("Approved {name} ({sha256})");
+        trust.approve(name, &plugin.path, sha256);
+    }
+    trust.save()
+}
 
-    // Validate the plugin path
-    let root = env::current_exe().unwrap();
-    let root = root.with_file_name("plugins");
-    println!("{}", root.to_str().unwrap());
-    let mut path = root.as_path();
-    if !args.plugin_path.is_empty() {
-        path = Path::new(&args.plugin_path);
+/// Returns an error if any of the plugin paths passed in isn't an existing directory
+fn validate_plugin_paths(paths: &[PathBuf]) -> Result<()> {
+    for path in paths {
         if !path.exists() {
-            return Err(anyhow!("Plugin path: {} does not exist!", args.plugin_path));
+            return Err(anyhow!("Plugin path: {} does not exist!", path.display()));
         } else if !path.is_dir() {
             return Err(anyhow!(
                 "Plugin path: {} is not a directory",
-                args.plugin_path
+                path.display()
             ));
         }
     }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    if args.print_schema {
+        println!("{}", parsing::SCHEMA);
+        return Ok(());
+    }
+
+    validate_plugin_paths(&args.plugin_path)?;
+    let mut plugin_dirs = args.plugin_path.clone();
+    plugin_dirs.extend(plugins::default_dirs());
+
+    // Apply any changes to which plugins are approved
+    let mut trust = trust::TrustStore::load()?;
+    update_trust(&args, &plugin_dirs, &mut trust)?;
+    if args.list_plugins {
+        let (found, _) = plugins::find(&plugin_dirs, false);
+        trust::print_plugins(&found, &trust);
+        return Ok(());
+    }
 
     // Get library index
-    let library = parsing::load_library(path, args.direct_json);
+    let mut config = config::Config::load(args.config.as_deref())?;
+    let new_items = config.new_items;
+    let profile = args
+        .profile
+        .as_deref()
+        .map(profile::Profile::load_named)
+        .transpose()?;
+    config.catalogs.extend(
+        args.catalog_url
+            .iter()
+            .cloned()
+            .map(config::RemoteCatalog::new),
+    );
+    let load_options = parsing::LoadOptions {
+        direct_json: args.direct_json,
+        timeout: Duration::from_secs(args.plugin_timeout),
+        cache_ttl: Duration::from_secs(args.cache_ttl.saturating_mul(60 * 60)),
+        refresh: args.refresh,
+        offline: args.offline,
+        config,
+        allow_executables: args.allow_executables,
+        trust,
+    };
+    let loader = parsing::load_library(&plugin_dirs, &load_options);
 
     // Build app object
-    let mut app = App::new(library);
+    let options = download::Options {
+        prefer_http: args.prefer_http,
+        rsync_snapshots: args.rsync_snapshots,
+    };
+    let mut app = App::new(
+        parsing::new_root(),
+        loader,
+        args.out_path.clone(),
+        options.clone(),
+        new_items,
+    );
+    if let Some((profile, path)) = profile {
+        app.use_profile(path, profile);
+    }
+    if let Some(budget) = args.budget.clone() {
+        if budget::parse_capacity(&budget).is_none() {
+            return Err(anyhow!(
+                "Budget {budget} is not a size, such as 256GB or 1TB"
+            ));
+        }
+        app.fit_when_loaded(budget);
+    }
 
     // Init term ui
     let backend = CrosstermBackend::new(std::io::stderr());
@@ -84,14 +234,36 @@ fn main() -> Result<()> {
     // Close down the term ui stuff cleanly
     tui.exit()?;
 
+    // Report any problems with the plugins
+    for diagnostic in &app.report.diagnostics {
+        eprintln!("{diagnostic}");
+    }
+    println!("{}", app.report.summary());
+    if app.is_loading() {
+        println!("Some plugins had not finished loading, and were left out");
+    }
+
     // Download stuff
-    let path = args.out_path;
     if app.download {
-        download::setup_folder(&path)?;
-        app.category.items.iter().for_each(|item| {
-            download::get_item(&path, item, args.prefer_http);
-        });
+        download_library(&app.category, &args.out_path, &options)?;
     }
 
     Ok(())
 }
+
+/// Downloads everything enabled in the library under `root` into the folder at `path`, then lists
+/// anything that failed
+fn download_library(root: &types::Category, path: &str, options: &download::Options) -> Result<()> {
+    download::setup_folder(path)?;
+    let mut failed = Vec::new();
+    root.items.iter().for_each(|item| {
+        download::get_item(path, item, options, &mut failed);
+    });
+    if !failed.is_empty() {
+        eprintln!("{} item(s) failed to download:", failed.len());
+        for item in &failed {
+            eprintln!("  {item}");
+        }
+    }
+    Ok(())
+}
@@ -1,93 +1,920 @@
-use std::{fs, path::Path, process::Command};
-
-use rayon::iter::{ParallelBridge, ParallelIterator};
-
-use crate::types::Category;
-
-/// Loads a library of items from either executables or json files in the directory at the provided
-/// `path`. `direct_json` will make it ignore executables and load from json files instead if true
-// FIXME: Fix the unpleasant amount of unwraps in the code here
-pub fn load_library(path: &Path, direct_json: bool) -> Category {
-    let mut root = Category::new("Apocalypse Library".into(), vec![], false);
-
-    let results: Vec<Vec<String>> = if direct_json {
-        path.read_dir()
-            .unwrap()
-            .par_bridge()
-            .map(std::result::Result::unwrap)
-            .filter(|file| !file.path().is_dir())
-            .map(|file| {
-                let file_path = file.path();
-                let extension = file_path.extension();
-                if extension.is_some_and(|ext| ext.eq_ignore_ascii_case("json")) {
-                    println!("Loading fron file: {:?}", file.path());
-                    let str = fs::read_to_string(file.path()).unwrap();
-                    if str.contains('\n') {
-                        let mut coll: Vec<String> = Vec::new();
-                        for line in str.lines() {
-                            coll.push(line.to_string());
-                        }
-                        coll
-                    } else {
-                        vec![str]
-                    }
+use std::{
+    fmt, fs,
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
+
+use apocalypse_library_types::{self as sdk, VersionRecord, SCHEMA_VERSION};
+
+use crate::{
+    cache,
+    config::{Config, PluginInput, RemoteCatalog},
+    plugins::{self, Format, Kind, Plugin},
+    remote, scrape,
+    trust::{self, ApprovalRequest, Trust, TrustStore},
+    types::{Category, Document, LibraryItem},
+    wasm,
+};
+
+/// The Category that placeholders for executable plugins that aren't allowed to run are put in
+const UNAPPROVED: &str = "Unapproved Plugins";
+
+/// The catalog compiled into the binary, so that there's something to download without plugins
+const DEFAULT_CATALOG: &str = include_str!("../catalog/default.toml");
+
+/// The plugin name the items of `DEFAULT_CATALOG` are loaded as. Plugin items replace them
+pub const BUILTIN: &str = "built-in";
+
+pub use apocalypse_library_types::SCHEMA;
+
+#[derive(Debug, Clone)]
+/// A problem found in the output of a plugin
+pub struct Diagnostic {
+    /// The name of the plugin that produced the output
+    pub plugin: String,
+    /// The line of the output the problem was found on, starting from one. Zero if the problem
+    /// is with the plugin as a whole
+    pub line: usize,
+    /// A description of the problem
+    pub message: String,
+    /// If the item was rejected, rather than loaded with a warning
+    pub rejected: bool,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.rejected { "error" } else { "warning" };
+        if self.line == 0 {
+            write!(f, "{kind}: {}: {}", self.plugin, self.message)
+        } else {
+            write!(f, "{kind}: {}:{}: {}", self.plugin, self.line, self.message)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// A summary of the output of a single plugin
+pub struct PluginReport {
+    /// The name of the plugin
+    pub name: String,
+    /// How many items were loaded from the plugin
+    pub items: usize,
+    /// How many items in the output were rejected
+    pub rejected: usize,
+    /// Why the plugin failed, if it did. A failed plugin has none of its items loaded
+    pub error: Option<String>,
+    /// When the plugin produced the loaded output, in seconds since the unix epoch. Not set for
+    /// static json files
+    pub fetched: Option<i64>,
+    /// If the output was loaded from the cache rather than by running the plugin
+    pub cached: bool,
+}
+
+impl PluginReport {
+    /// Returns a description of how old the data loaded from this plugin is
+    pub fn age(&self) -> Option<String> {
+        let fetched = self.fetched?;
+        let minutes = (Utc::now().timestamp() - fetched).max(0) / 60;
+        let age = match minutes {
+            0 => "just now".to_string(),
+            1..=59 => format!("{minutes} minute(s) ago"),
+            60..=1439 => format!("{} hour(s) ago", minutes / 60),
+            _ => format!("{} day(s) ago", minutes / 1440),
+        };
+        if self.cached {
+            Some(format!("cached, from {age}"))
+        } else {
+            Some(format!("from {age}"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// A summary of loading the library from all plugins
+pub struct LoadReport {
+    /// A report for each plugin that was run
+    pub plugins: Vec<PluginReport>,
+    /// Every problem found in the plugin output
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl LoadReport {
+    /// Returns the total number of items loaded from all plugins
+    pub fn items_loaded(&self) -> usize {
+        self.plugins.iter().map(|plugin| plugin.items).sum()
+    }
+
+    /// Returns the total number of items rejected from all plugins
+    pub fn items_rejected(&self) -> usize {
+        self.plugins.iter().map(|plugin| plugin.rejected).sum()
+    }
+
+    /// Returns the number of plugins that failed
+    pub fn plugins_failed(&self) -> usize {
+        self.plugins
+            .iter()
+            .filter(|plugin| plugin.error.is_some())
+            .count()
+    }
+
+    /// Returns a one line summary of the load
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} plugin(s) run, {} item(s) loaded, {} item(s) rejected",
+            self.plugins.len(),
+            self.items_loaded(),
+            self.items_rejected()
+        );
+        let failed = self.plugins_failed();
+        if failed > 0 {
+            summary = format!("{summary}, {failed} plugin(s) failed");
+        }
+        summary
+    }
+}
+
+/// Parses a single item written in schema `version` from the `deserializer`, returning the item
+/// and the paths of any fields that were not recognised. Older versions are migrated to the
+/// current model
+fn parse_item<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    version: u32,
+) -> Result<(LibraryItem, Vec<String>), D::Error> {
+    let mut unknown = Vec::new();
+    let ignored = |path: serde_ignored::Path| {
+        // Enum variants show up as `?` in the path, which is just noise for plugin authors
+        unknown.push(path.to_string().replace("?.", ""));
+    };
+    let item: sdk::LibraryItem = if version < 2 {
+        serde_ignored::deserialize::<_, _, sdk::v1::LibraryItem>(deserializer, ignored)?.into()
+    } else {
+        serde_ignored::deserialize(deserializer, ignored)?
+    };
+    Ok((item.into(), unknown))
+}
+
+/// Parses a single item written in schema `version` from the JSON `text`
+fn parse_json_item(text: &str, version: u32) -> Result<(LibraryItem, Vec<String>), String> {
+    let mut deserializer = serde_json::Deserializer::from_str(text);
+    let item = parse_item(&mut deserializer, version).map_err(|err| err.to_string())?;
+    deserializer.end().map_err(|err| err.to_string())?;
+    Ok(item)
+}
+
+/// Returns the version declared by the JSON `text`, if it is a `VersionRecord` rather than an item
+fn json_version(text: &str) -> Option<u32> {
+    if !text.starts_with('{') || !text.contains("\"schema_version\"") {
+        return None;
+    }
+    serde_json::from_str::<VersionRecord>(text)
+        .ok()
+        .map(|record| record.schema_version)
+}
+
+/// Returns the line number, starting from one, that `part` starts on within `text`. `part` must
+/// be a slice of `text`
+fn line_of(text: &str, part: &str) -> usize {
+    let offset = (part.as_ptr() as usize).saturating_sub(text.as_ptr() as usize);
+    text.get(..offset)
+        .map_or(0, |before| before.matches('\n').count())
+        + 1
+}
+
+/// How the output of a plugin is laid out, worked out from the first line of it
+#[derive(Debug)]
+enum Shape {
+    /// Nothing other than blank lines has been seen yet
+    Unknown,
+    /// Every line is a complete JSON item, or array of items, so each is parsed as it arrives
+    Lines,
+    /// The output is a JSON document spread over multiple lines, such as pretty printed JSON, so
+    /// it is collected and parsed as a whole once the output is complete
+    Document {
+        /// The output collected so far
+        text: String,
+        /// The line the document started on
+        first_line: usize,
+    },
+}
+
+/// Settings that control how plugins are loaded
+#[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct LoadOptions {
+    /// Only load static catalog files, ignoring executable and WASM plugins
+    pub direct_json: bool,
+    /// How long each plugin may run for before it is stopped
+    pub timeout: Duration,
+    /// How long the cached output of a plugin is used for before the plugin is run again
+    pub cache_ttl: Duration,
+    /// Run every plugin, ignoring any cached output
+    pub refresh: bool,
+    /// Only load plugin output from the cache, never running any plugins
+    pub offline: bool,
+    /// The user's configuration, which plugins are passed their settings from
+    pub config: Config,
+    /// Run every executable plugin, rather than only those the user has approved
+    pub allow_executables: bool,
+    /// The executable plugins the user has approved
+    pub trust: TrustStore,
+}
+
+/// An update from the plugins as they load
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum LoadEvent {
+    /// The named plugin has started loading
+    Started(String),
+    /// An item has been loaded, its `Documents` have their source set to the plugin it came from
+    Item(LibraryItem),
+    /// A problem was found with a plugin or its output
+    Diagnostic(Diagnostic),
+    /// Everything loaded so far from the named plugin should be removed, as the plugin failed
+    Discard(String),
+    /// A plugin has finished loading
+    Finished(PluginReport),
+    /// An executable plugin is new or has changed, and is waiting for the user to approve it
+    Approval(ApprovalRequest),
+}
+
+/// Parses the output of a plugin one line at a time, sending each item as soon as it is parsed
+struct OutputParser<'a> {
+    /// Where the parsed items are sent
+    sender: &'a Sender<LoadEvent>,
+    /// The number of the last line parsed
+    line: usize,
+    /// The report for the plugin, built up as its output is parsed
+    report: PluginReport,
+    /// If any of the output has been invalid UTF-8 so far
+    invalid_utf8: bool,
+    /// How the output is laid out
+    shape: Shape,
+    /// The schema version of the output, set by the last `VersionRecord`
+    version: u32,
+}
+
+impl<'a> OutputParser<'a> {
+    fn new(name: &str, sender: &'a Sender<LoadEvent>) -> Self {
+        // If the receiver has gone the app has closed, so there's no one to tell
+        let _ = sender.send(LoadEvent::Started(name.to_string()));
+        Self {
+            sender,
+            line: 0,
+            report: PluginReport {
+                name: name.to_string(),
+                ..Default::default()
+            },
+            invalid_utf8: false,
+            shape: Shape::Unknown,
+            version: 1,
+        }
+    }
+
+    /// Sends a diagnostic for this plugin
+    fn diagnostic(&self, line: usize, message: String, rejected: bool) {
+        let _ = self.sender.send(LoadEvent::Diagnostic(Diagnostic {
+            plugin: self.report.name.clone(),
+            line,
+            message,
+            rejected,
+        }));
+    }
+
+    /// Parses a single line of output, and sends the items on it if they are valid. Lines that
+    /// are part of a multi line document are collected until `finish` is called
+    fn parse_line(&mut self, line: &[u8]) {
+        self.line += 1;
+        let line = String::from_utf8(line.to_vec()).unwrap_or_else(|err| {
+            if !self.invalid_utf8 {
+                self.invalid_utf8 = true;
+                let message = "output is not valid UTF-8, invalid characters were replaced";
+                self.diagnostic(self.line, message.into(), false);
+            }
+            String::from_utf8_lossy(err.as_bytes()).into_owned()
+        });
+        match &mut self.shape {
+            Shape::Document { text, .. } => {
+                text.push_str(&line);
+                text.push('\n');
+                return;
+            }
+            Shape::Lines => {}
+            Shape::Unknown if line.trim().is_empty() => return,
+            Shape::Unknown => {
+                // A line that isn't valid JSON on its own must be the start of a larger document
+                if serde_json::from_str::<&RawValue>(&line).is_ok() {
+                    self.shape = Shape::Lines;
                 } else {
-                    vec![]
+                    self.shape = Shape::Document {
+                        text: format!("{line}\n"),
+                        first_line: self.line,
+                    };
+                    return;
                 }
-            })
-            .collect()
-    } else {
-        path.read_dir()
-            .unwrap()
-            .par_bridge()
-            .map(|file| {
-                let mut coll: Vec<String> = Vec::new();
-                let file = file.unwrap();
-                let file_path = file.path();
-                if file_path.is_dir() {
-                    return vec![];
+            }
+        }
+        if !line.trim().is_empty() {
+            self.parse_json_value(line.trim(), self.line);
+        }
+    }
+
+    /// Switches to schema `version` for the items that follow, found on `line`
+    fn set_version(&mut self, version: u32, line: usize) {
+        if version == 0 {
+            self.reject(line, "schema_version must be 1 or higher".into());
+            return;
+        }
+        if version > SCHEMA_VERSION {
+            let message = format!(
+                "output uses schema version {version}, but this version of the downloader only \
+                 understands up to version {SCHEMA_VERSION}. Items are read as version \
+                 {SCHEMA_VERSION} and anything newer is ignored, update the downloader to use them"
+            );
+            self.diagnostic(line, message, false);
+        }
+        self.version = version;
+    }
+
+    /// Parses a JSON value found on `line`, which is either a single item, a `VersionRecord` or an
+    /// array of them
+    fn parse_json_value(&mut self, value: &str, line: usize) {
+        if !value.starts_with('[') {
+            self.parse_json_element(value, line);
+            return;
+        }
+        match serde_json::from_str::<Vec<&RawValue>>(value) {
+            Ok(items) => {
+                for item in items {
+                    self.parse_json_element(item.get(), line + line_of(value, item.get()) - 1);
+                }
+            }
+            Err(err) => self.reject(line, err.to_string()),
+        }
+    }
+
+    /// Parses a single JSON item or `VersionRecord` found on `line`
+    fn parse_json_element(&mut self, value: &str, line: usize) {
+        if let Some(version) = json_version(value) {
+            self.set_version(version, line);
+        } else {
+            let result = parse_json_item(value, self.version);
+            self.add_item(result, line);
+        }
+    }
+
+    /// Parses a JSON document made up of any number of values, which may span multiple lines.
+    /// `first_line` is the line of the output the document starts on
+    fn parse_json_document(&mut self, text: &str, first_line: usize) {
+        let mut values = serde_json::Deserializer::from_str(text).into_iter::<&RawValue>();
+        loop {
+            match values.next() {
+                Some(Ok(value)) => {
+                    let line = first_line + line_of(text, value.get()) - 1;
+                    self.parse_json_value(value.get(), line);
                 }
-                let extension = file_path.extension();
-                if (!crate::IS_WINDOWS && extension.is_some())
-                    || (crate::IS_WINDOWS
-                        && !extension.is_some_and(|ext| ext.eq_ignore_ascii_case("exe")))
-                {
-                    return vec![];
+                // There's no way to find where the next value starts after a syntax error
+                Some(Err(err)) => {
+                    self.reject(first_line + err.line().max(1) - 1, err.to_string());
+                    break;
                 }
-                let output = Command::new(file.path()).output().unwrap();
-                assert!(
-                    output.status.success(),
-                    "Command: {:?} failed with output: {}{}",
-                    file.file_name(),
-                    String::from_utf8(output.stdout).unwrap(),
-                    String::from_utf8(output.stderr).unwrap()
-                );
-                let str = String::from_utf8(output.stdout).unwrap();
-                if str.contains('\n') {
-                    for line in str.lines() {
-                        coll.push(line.to_string());
+                None => break,
+            }
+        }
+    }
+
+    /// Parses a whole catalog file in the provided `format`
+    fn parse_catalog(&mut self, data: &[u8], format: Format) {
+        match format {
+            Format::Json => self.parse_all(data),
+            Format::Toml => {
+                let text = String::from_utf8_lossy(data);
+                match toml::from_str::<toml::Table>(&text) {
+                    Ok(mut table) => {
+                        match table.remove("schema_version").map(u32::deserialize) {
+                            Some(Ok(version)) => self.set_version(version, 0),
+                            Some(Err(err)) => self.reject(0, format!("schema_version: {err}")),
+                            None => {}
+                        }
+                        match table.remove("items") {
+                            Some(toml::Value::Array(items)) => {
+                                for item in items {
+                                    let result = parse_item(item, self.version)
+                                        .map_err(|err| err.to_string());
+                                    self.add_item(result, 0);
+                                }
+                            }
+                            Some(_) => self.reject(0, "'items' must be an array of tables".into()),
+                            None => {
+                                let result =
+                                    parse_item(table, self.version).map_err(|err| err.to_string());
+                                self.add_item(result, 0);
+                            }
+                        }
                     }
+                    Err(err) => self.reject(0, err.to_string()),
+                }
+            }
+            Format::Yaml => {
+                let text = String::from_utf8_lossy(data);
+                for document in serde_yaml::Deserializer::from_str(&text) {
+                    // YAML values only read enums written with tags, so go through JSON values to
+                    // accept the same `Document: ...` form as every other format
+                    match serde_json::Value::deserialize(document) {
+                        Ok(serde_json::Value::Array(items)) => {
+                            for item in items {
+                                self.parse_value(item);
+                            }
+                        }
+                        Ok(serde_json::Value::Null) => {}
+                        Ok(item) => self.parse_value(item),
+                        Err(err) => {
+                            let line = err.location().map_or(0, |location| location.line());
+                            self.reject(line, err.to_string());
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Parses a single item or `VersionRecord` from a catalog that has already been read into a
+    /// JSON value
+    fn parse_value(&mut self, value: serde_json::Value) {
+        if value.get("schema_version").is_some() {
+            match VersionRecord::deserialize(value) {
+                Ok(record) => self.set_version(record.schema_version, 0),
+                Err(err) => self.reject(0, err.to_string()),
+            }
+        } else {
+            let result = parse_item(value, self.version).map_err(|err| err.to_string());
+            self.add_item(result, 0);
+        }
+    }
+
+    /// Sends the parsed item, or records why it was rejected. `line` is where the item was found,
+    /// or zero if that isn't known
+    fn add_item(&mut self, result: Result<(LibraryItem, Vec<String>), String>, line: usize) {
+        match result {
+            Ok((item, unknown)) => {
+                // Fields from a newer version are expected, which `set_version` already warned about
+                let unknown = if self.version > SCHEMA_VERSION {
+                    Vec::new()
                 } else {
-                    coll.push(str);
+                    unknown
+                };
+                for path in unknown {
+                    let message = format!("unknown field '{path}' was ignored");
+                    self.diagnostic(line, message, false);
                 }
-                coll
-            })
-            .collect()
+                let mut item = item.nest();
+                item.set_source(&self.report.name);
+                self.report.items += 1;
+                let _ = self.sender.send(LoadEvent::Item(item));
+            }
+            Err(err) => self.reject(line, err),
+        }
+    }
+
+    /// Records that an item on `line` was rejected because of `error`
+    fn reject(&mut self, line: usize, error: String) {
+        self.diagnostic(line, error, true);
+        self.report.rejected += 1;
+    }
+
+    /// Parses all of the provided `output`
+    fn parse_all(&mut self, output: &[u8]) {
+        output.split(|byte| *byte == b'\n').for_each(|line| {
+            self.parse_line(line);
+        });
+    }
+
+    /// Removes everything sent so far, so that the output can be parsed again from the start
+    fn discard(&mut self) {
+        let _ = self
+            .sender
+            .send(LoadEvent::Discard(self.report.name.clone()));
+        self.line = 0;
+        self.report.items = 0;
+        self.report.rejected = 0;
+        self.invalid_utf8 = false;
+        self.shape = Shape::Unknown;
+        self.version = 1;
+    }
+
+    /// Records that the plugin failed with `error`, removing anything it sent
+    fn fail(mut self, error: String) {
+        self.discard();
+        self.diagnostic(0, error.clone(), true);
+        self.report.error = Some(error);
+        self.finish(None, false);
+    }
+
+    /// Parses any multi line document that was collected, then sends the final report for the
+    /// plugin
+    fn finish(mut self, fetched: Option<i64>, cached: bool) {
+        if let Shape::Document { text, first_line } =
+            std::mem::replace(&mut self.shape, Shape::Unknown)
+        {
+            self.parse_json_document(&text, first_line);
+        }
+        self.report.fetched = fetched;
+        self.report.cached = cached;
+        let _ = self.sender.send(LoadEvent::Finished(self.report));
+    }
+}
+
+/// The output of a plugin as stored in the cache
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedOutput {
+    /// When the plugin was run, in seconds since the unix epoch
+    fetched: i64,
+    /// What the plugin output
+    output: String,
+    /// The `PluginInput` the plugin was run with, as JSON. Output from a plugin run with different
+    /// settings isn't reused
+    #[serde(default)]
+    input: String,
+}
+
+/// Returns the name of the cache file for the plugin called `name`
+fn cache_name(name: &str) -> String {
+    format!("plugin-{name}.json")
+}
+
+/// Gets the output of the executable or WASM plugin at `path`, either from the cache or by running
+/// it depending on the `options`, and sends its items to `sender`. If running the plugin fails,
+/// any cached output is used instead
+fn load_plugin(
+    name: &str,
+    path: &Path,
+    kind: Kind,
+    options: &LoadOptions,
+    sender: &Sender<LoadEvent>,
+) {
+    let mut parser = OutputParser::new(name, sender);
+    let mut approved = None;
+    if kind == Kind::Executable && !options.allow_executables {
+        match check_approval(name, path, options, sender) {
+            Ok(sha256) => approved = Some(sha256),
+            Err(reason) => {
+                parser.fail(reason.clone());
+                // Show the plugin in the library, so that it's clear why its items are missing
+                let placeholder = Document::disabled(name.to_string(), reason, UNAPPROVED.into());
+                let _ = sender.send(LoadEvent::Item(LibraryItem::Document(placeholder).nest()));
+                return;
+            }
+        }
+    }
+    let input = PluginInput::new(name, &options.config);
+    let mut input_json = serde_json::to_string(&input).unwrap_or_default();
+    if kind == Kind::Source {
+        // Sources are scraped again whenever they are edited
+        input_json.push_str(&fs::read_to_string(path).unwrap_or_default());
+    }
+    let cached: Option<CachedOutput> = cache::load(&cache_name(name));
+    let now = Utc::now().timestamp();
+    let ttl = i64::try_from(options.cache_ttl.as_secs()).unwrap_or(i64::MAX);
+    if let Some(cached) = &cached {
+        let fresh = now - cached.fetched < ttl && cached.input == input_json;
+        if options.offline || (!options.refresh && fresh) {
+            parser.parse_all(cached.output.as_bytes());
+            parser.finish(Some(cached.fetched), true);
+            return;
+        }
+    }
+    if options.offline {
+        parser.fail("no cached output to use while offline".into());
+        return;
+    }
+
+    let result = match kind {
+        Kind::Wasm => {
+            let grants = options.config.grants(name);
+            wasm::run(path, &input, &grants, options.timeout)
+        }
+        Kind::Source => scrape::run(path, options.timeout),
+        _ => run_plugin(
+            path,
+            approved.as_deref(),
+            &input,
+            options.timeout,
+            &mut parser,
+        ),
+    };
+    if kind != Kind::Executable {
+        if let Ok(data) = &result {
+            parser.parse_all(data);
+        }
+    }
+    match result {
+        Ok(data) => {
+            let output = CachedOutput {
+                fetched: now,
+                output: String::from_utf8_lossy(&data).into_owned(),
+                input: input_json,
+            };
+            if let Err(err) = cache::save(&cache_name(name), &output) {
+                parser.diagnostic(0, format!("failed to cache output: {err}"), false);
+            }
+            parser.finish(Some(now), false);
+        }
+        Err(err) => match cached {
+            // Stale data is better than no data
+            Some(cached) => {
+                parser.discard();
+                parser.diagnostic(0, format!("{err}, using cached output instead"), false);
+                parser.parse_all(cached.output.as_bytes());
+                parser.finish(Some(cached.fetched), true);
+            }
+            None => parser.fail(err),
+        },
+    }
+}
+
+/// Checks that the executable plugin called `name` has been approved to run, asking the user
+/// first if it is new or has changed since it was approved. Returns the SHA-256 it was approved
+/// with, or why it can't run if not
+fn check_approval(
+    name: &str,
+    path: &Path,
+    options: &LoadOptions,
+    sender: &Sender<LoadEvent>,
+) -> Result<String, String> {
+    let sha256 = trust::hash_file(path).map_err(|err| format!("failed to read: {err}"))?;
+    let trust = options.trust.check(name, &sha256);
+    if trust == Trust::Approved {
+        return Ok(sha256);
+    }
+    let (reply, answer) = mpsc::channel();
+    let request = ApprovalRequest {
+        name: name.to_string(),
+        path: path.to_path_buf(),
+        sha256: sha256.clone(),
+        trust,
+        reply,
     };
+    // No answer means the app closed before the user decided, which is the same as a no
+    if sender.send(LoadEvent::Approval(request)).is_ok() && answer.recv() == Ok(true) {
+        return Ok(sha256);
+    }
+    Err(if trust == Trust::Changed {
+        format!("changed since it was approved, approve it again or run with --approve {name}")
+    } else {
+        format!("not approved to run, approve it or run with --approve {name}")
+    })
+}
+
+/// Spawns a thread that reads everything from `source` until it closes, then sends it
+fn read_to_end(mut source: impl Read + Send + 'static) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        // If reading fails part way through we still want whatever we got up to that point
+        let _ = source.read_to_end(&mut buffer);
+        let _ = sender.send(buffer);
+    });
+    receiver
+}
+
+/// Spawns a thread that reads `source` a line at a time, sending each line, along with its
+/// newline, as soon as it arrives
+fn read_lines(source: impl Read + Send + 'static) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(source);
+        loop {
+            let mut line = Vec::new();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                // Nobody is listening once the plugin has timed out
+                Ok(_) if sender.send(line).is_err() => break,
+                Ok(_) => {}
+            }
+        }
+    });
+    receiver
+}
+
+/// Spawns a thread that waits for `child` to exit, killing it if it takes longer than `timeout`
+fn watch(mut child: Child, timeout: Duration) -> thread::JoinHandle<Result<ExitStatus, String>> {
+    thread::spawn(move || {
+        let start = Instant::now();
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => return Ok(status),
+                Ok(None) if start.elapsed() >= timeout => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(format!("timed out after {} seconds", timeout.as_secs()));
+                }
+                Ok(None) => thread::sleep(Duration::from_millis(50)),
+                Err(err) => return Err(format!("failed while waiting for it to exit: {err}")),
+            }
+        }
+    })
+}
+
+/// Runs the plugin executable at `path` with the provided `input`, parsing each line of its
+/// output as it arrives. Returns the complete output. Fails if the plugin could not be started,
+/// exits unsuccessfully or takes longer than `timeout` to finish. If the plugin had to be
+/// `approved`, it is refused unless it still has the SHA-256 it was approved with
+fn run_plugin(
+    path: &Path,
+    approved: Option<&str>,
+    input: &PluginInput,
+    timeout: Duration,
+    parser: &mut OutputParser,
+) -> Result<Vec<u8>, String> {
+    if let Some(cache_dir) = &input.cache_dir {
+        if let Err(err) = fs::create_dir_all(cache_dir) {
+            parser.diagnostic(0, format!("failed to create cache dir: {err}"), false);
+        }
+    }
+    // The plugin could have been replaced while the user was deciding, so hash it again as late
+    // as possible
+    if let Some(sha256) = approved {
+        let current = trust::hash_file(path).map_err(|err| format!("failed to read: {err}"))?;
+        if current != sha256 {
+            return Err("changed after it was approved, refusing to run it".into());
+        }
+    }
+    let mut child = Command::new(path)
+        .envs(input.env_vars())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("failed to start: {err}"))?;
+    // Write the input on another thread, so a plugin that never reads it can't block us. Dropping
+    // stdin once it's written lets the plugin see the end of the input
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let json = serde_json::to_vec(input).unwrap_or_default();
+    thread::spawn(move || {
+        // Plugins that don't need the input may exit without reading it
+        let _ = stdin.write_all(&json);
+    });
+    // Output is read on other threads, as processes the plugin starts can keep it open after the
+    // plugin exits, and we stop waiting for it once the plugin has had `timeout` to finish
+    let deadline = Instant::now() + timeout;
+    let lines = read_lines(child.stdout.take().expect("stdout is piped"));
+    // Read stderr on another thread, so that the plugin can't block on a full pipe
+    let stderr = read_to_end(child.stderr.take().expect("stderr is piped"));
+    let status = watch(child, timeout);
+
+    let mut output = Vec::new();
+    let mut timed_out = false;
+    loop {
+        match lines.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(mut line) => {
+                output.extend_from_slice(&line);
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                }
+                parser.parse_line(&line);
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                timed_out = true;
+                break;
+            }
+        }
+    }
 
-    results
-        .into_iter()
-        .flatten()
-        .flat_map(|str| serde_json::from_str(&str))
-        .for_each(|item| {
-            root.add(item);
+    let status = status
+        .join()
+        .unwrap_or_else(|_| Err("failed while waiting for it to exit".into()))?;
+    if timed_out {
+        return Err(format!(
+            "timed out after {} seconds waiting for its output to close",
+            timeout.as_secs()
+        ));
+    }
+    let stderr = stderr
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .unwrap_or_default();
+    if status.success() {
+        Ok(output)
+    } else {
+        let stderr = String::from_utf8_lossy(&stderr);
+        Err(format!("exited with {status}: {}", stderr.trim()))
+    }
+}
+
+/// Loads a static catalog file at `path` in the provided `format`, sending its items to `sender`
+fn load_catalog(name: &str, path: &Path, format: Format, sender: &Sender<LoadEvent>) {
+    let mut parser = OutputParser::new(name, sender);
+    match fs::read(path) {
+        Ok(data) => {
+            parser.parse_catalog(&data, format);
+            parser.finish(None, false);
+        }
+        Err(err) => parser.fail(err.to_string()),
+    }
+}
+
+/// Loads the catalog compiled into the binary, sending its items to `sender`
+fn load_builtin(sender: &Sender<LoadEvent>) {
+    let mut parser = OutputParser::new(BUILTIN, sender);
+    parser.parse_catalog(DEFAULT_CATALOG.as_bytes(), Format::Toml);
+    parser.finish(None, false);
+}
+
+/// Fetches the remote `catalog`, sending its items to `sender`
+fn load_remote(catalog: &RemoteCatalog, options: &LoadOptions, sender: &Sender<LoadEvent>) {
+    let mut parser = OutputParser::new(&catalog.url, sender);
+    match remote::fetch(catalog, options) {
+        Ok(fetched) => {
+            if let Some(warning) = fetched.warning {
+                parser.diagnostic(0, warning, false);
+            }
+            parser.parse_catalog(&fetched.body, remote::format(&catalog.url));
+            parser.finish(fetched.fetched, fetched.cached);
+        }
+        Err(err) => parser.fail(err),
+    }
+}
+
+/// Creates the empty root `Category` that the library is loaded into
+pub fn new_root() -> Category {
+    Category::new("Apocalypse Library".into(), vec![], false)
+}
+
+/// Starts loading a library of items from the plugins in the provided `dirs`, see
+/// `plugins::find` for how they're found. Plugins can either be executables or json, toml or yaml
+/// catalog files, `options.direct_json` will make it ignore executables if true. The remote
+/// catalogs in `options.config` are fetched alongside them. Executable output is cached, see
+/// `LoadOptions` for how the cache is used. The catalog compiled into the binary is always loaded
+/// first, as `BUILTIN`. The plugins are loaded in the background, with items sent through the
+/// returned `Receiver` as soon as they are parsed
+pub fn load_library(dirs: &[PathBuf], options: &LoadOptions) -> Receiver<LoadEvent> {
+    let (sender, receiver) = mpsc::channel();
+    let dirs = dirs.to_vec();
+    let options = options.clone();
+    thread::spawn(move || {
+        // The built-in items are sent before anything else, so that plugin items always replace
+        // them rather than the other way around
+        load_builtin(&sender);
+        let (found, diagnostics) = plugins::find(&dirs, options.direct_json);
+        for diagnostic in diagnostics {
+            let _ = sender.send(LoadEvent::Diagnostic(diagnostic));
+        }
+        // Plugins spend most of their time waiting on the network rather than using the CPU, so
+        // each gets its own thread instead of sharing a pool sized to the number of cores
+        thread::scope(|scope| {
+            for Plugin { name, path, kind } in found {
+                let (options, sender) = (&options, &sender);
+                scope.spawn(move || match kind {
+                    Kind::Executable | Kind::Wasm | Kind::Source => {
+                        load_plugin(&name, &path, kind, options, sender);
+                    }
+                    Kind::Catalog(format) => load_catalog(&name, &path, format, sender),
+                });
+            }
+            for catalog in &options.config.catalogs {
+                let (options, sender) = (&options, &sender);
+                scope.spawn(move || load_remote(catalog, options, sender));
+            }
         });
+    });
+    receiver
+}
 
-    root.fix_counter(); // Will recursively fix the list counter objects of all contained
-                        // categories
-    root.items.iter_mut().for_each(|item| {
-        item.set_enabled_recursive();
-    }); // Getting some odd behavior, this should fix it
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    root
+    #[test]
+    fn builtin_catalog_has_every_collection() {
+        let (sender, receiver) = mpsc::channel();
+        load_builtin(&sender);
+        drop(sender);
+        let mut root = new_root();
+        for event in receiver {
+            match event {
+                LoadEvent::Item(item) => root.add(item),
+                LoadEvent::Diagnostic(diagnostic) => panic!("{diagnostic}"),
+                _ => {}
+            }
+        }
+        for id in [
+            "kiwix-wikipedia-en-maxi",
+            "kiwix-mdwiki-en",
+            "survivor-library",
+            "osm-planet",
+            "osm-europe",
+            "iso-debian-12-dvd-amd64",
+            "iso-arch-x86_64",
+        ] {
+            assert!(root.find_document(id).is_some(), "{id} is missing");
+        }
+    }
 }
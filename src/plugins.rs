@@ -0,0 +1,191 @@
+use std::{
+    collections::HashSet,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{parsing::Diagnostic, scrape};
+
+/// How many levels of sub folders are searched for plugins, this also stops symlink loops
+const MAX_DEPTH: usize = 8;
+
+/// The format of a static catalog file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A single item, an array of items, or any number of items one after another, as JSON
+    Json,
+    /// A TOML table, either a single item or an `items` array of them
+    Toml,
+    /// A YAML document, either a single item or a sequence of them. Multiple documents may be
+    /// separated by `---`
+    Yaml,
+}
+
+impl Format {
+    /// Returns the format of the catalog file at `path` from its extension, if it is a catalog
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "json" | "ndjson" => Some(Self::Json),
+            "toml" => Some(Self::Toml),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// How a plugin provides its items
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// An executable that outputs items when run, with full access to the system
+    Executable,
+    /// A WASI module that outputs items when run, in a sandbox with only the access it has been
+    /// granted
+    Wasm,
+    /// A static catalog file of items
+    Catalog(Format),
+    /// A declarative `.source.toml` file describing how to scrape a site into items
+    Source,
+}
+
+/// A plugin found in one of the plugin folders
+#[derive(Debug, Clone)]
+pub struct Plugin {
+    /// The file name of the plugin, plugins with the same name replace each other
+    pub name: String,
+    /// Where the plugin is
+    pub path: PathBuf,
+    /// How the plugin provides its items
+    pub kind: Kind,
+}
+
+/// Returns the folders that are searched for plugins by default, in order of precedence
+pub fn default_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(data) = dirs::data_dir() {
+        dirs.push(data.join("apocalypse_library").join("plugins"));
+    }
+    if let Ok(exe) = env::current_exe() {
+        dirs.push(exe.with_file_name("plugins"));
+    }
+    if !crate::IS_WINDOWS {
+        dirs.push(PathBuf::from("/usr/local/share/apocalypse_library/plugins"));
+        dirs.push(PathBuf::from("/usr/share/apocalypse_library/plugins"));
+    }
+    dirs
+}
+
+/// Returns the kind of plugin at `path`, if it is one. With `catalogs_only` executables, WASM
+/// modules and sources are ignored
+fn kind_of(path: &Path, catalogs_only: bool) -> Option<Kind> {
+    let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+    if name.ends_with(scrape::SOURCE_SUFFIX) {
+        return (!catalogs_only).then_some(Kind::Source);
+    }
+    if let Some(format) = Format::from_path(path) {
+        return Some(Kind::Catalog(format));
+    }
+    if catalogs_only {
+        return None;
+    }
+    if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("wasm"))
+    {
+        return Some(Kind::Wasm);
+    }
+    let executable = if crate::IS_WINDOWS {
+        path.extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("exe"))
+    } else {
+        is_executable(path)
+    };
+    executable.then_some(Kind::Executable)
+}
+
+/// Returns if the file at `path` has any of its executable permission bits set
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|meta| meta.permissions().mode() & 0o111 != 0)
+}
+
+/// Returns if the file at `path` has no extension, as there are no permission bits to check
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.extension().is_none()
+}
+
+/// Adds every plugin in `dir` and its sub folders to `plugins`, in name order
+fn search(
+    dir: &Path,
+    depth: usize,
+    catalogs_only: bool,
+    plugins: &mut Vec<Plugin>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            diagnostics.push(Diagnostic {
+                plugin: dir.display().to_string(),
+                line: 0,
+                message: format!("could not be read: {err}"),
+                rejected: true,
+            });
+            return;
+        }
+    };
+    let mut entries: Vec<_> = entries.filter_map(Result::ok).collect();
+    entries.sort_by_key(fs::DirEntry::file_name);
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Skip hidden files, such as the .git folder of a plugin repository
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        if path.is_dir() {
+            if depth < MAX_DEPTH {
+                search(&path, depth + 1, catalogs_only, plugins, diagnostics);
+            }
+        } else if let Some(kind) = kind_of(&path, catalogs_only) {
+            plugins.push(Plugin { name, path, kind });
+        }
+    }
+}
+
+/// Finds every plugin in the provided `dirs` and their sub folders. `dirs` are in order of
+/// precedence, when more than one plugin has the same name only the first one found is used.
+/// Folders that don't exist are skipped. With `catalogs_only` executables, WASM modules and
+/// sources are ignored
+pub fn find(dirs: &[PathBuf], catalogs_only: bool) -> (Vec<Plugin>, Vec<Diagnostic>) {
+    let mut found = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut searched = HashSet::new();
+    for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+        // The same folder can show up more than once, such as the data dir being next to the exe
+        if searched.insert(fs::canonicalize(dir).unwrap_or_else(|_| dir.clone())) {
+            search(dir, 0, catalogs_only, &mut found, &mut diagnostics);
+        }
+    }
+
+    let mut plugins: Vec<Plugin> = Vec::new();
+    for plugin in found {
+        if let Some(used) = plugins.iter().find(|used| used.name == plugin.name) {
+            diagnostics.push(Diagnostic {
+                message: format!(
+                    "{} is replaced by {}",
+                    plugin.path.display(),
+                    used.path.display()
+                ),
+                plugin: plugin.name,
+                line: 0,
+                rejected: false,
+            });
+        } else {
+            plugins.push(plugin);
+        }
+    }
+    (plugins, diagnostics)
+}
@@ -0,0 +1,280 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Category, LibraryItem};
+
+/// The extension of profile files in the profiles folder
+const EXTENSION: &str = "json";
+
+/// If items that weren't in the library when a profile was saved are downloaded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NewItems {
+    /// New items are enabled, so that nothing new is missed
+    #[default]
+    Enabled,
+    /// New items are disabled, so that only what was chosen is downloaded
+    Disabled,
+}
+
+/// Which items the user has enabled, saved so that the same selection can be downloaded again
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Profile {
+    /// If each Document is enabled, by id
+    #[serde(default)]
+    pub documents: BTreeMap<String, bool>,
+    /// If each Category is enabled, by id, or by its path such as `Kiwix/Wikipedia` if it
+    /// doesn't have one
+    #[serde(default)]
+    pub categories: BTreeMap<String, bool>,
+    /// The item that was enabled in each single selection Category, by the key of the Category,
+    /// see `categories`. Items are Document ids or Category keys
+    #[serde(default)]
+    pub choices: BTreeMap<String, String>,
+    /// When the profile was last saved, in seconds since the unix epoch
+    #[serde(default)]
+    pub saved: i64,
+}
+
+/// Returns the key `cat` is saved under, inside the Category at `parent`
+fn category_key(cat: &Category, parent: &str) -> String {
+    cat.id()
+        .map_or_else(|| child_path(parent, cat.name()), str::to_string)
+}
+
+/// Returns the key `item` is saved under, inside the Category at `parent`
+fn item_key(item: &LibraryItem, parent: &str) -> String {
+    match item {
+        LibraryItem::Document(doc) => doc.id().to_string(),
+        LibraryItem::Category(cat) => category_key(cat, parent),
+    }
+}
+
+/// Returns the path of the item called `name` inside the Category at `parent`
+fn child_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{parent}/{name}")
+    }
+}
+
+impl Profile {
+    /// Returns the folder that named profiles are kept in, next to the default config file
+    pub fn dir() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("apocalypse_library").join("profiles"))
+    }
+
+    /// Returns where the profile `name` is. Anything that looks like a path is used as is,
+    /// otherwise it is the name of a profile in `dir`
+    pub fn path(name: &str) -> Result<PathBuf> {
+        let path = Path::new(name);
+        if path.components().count() > 1 || path.extension().is_some() {
+            return Ok(path.to_path_buf());
+        }
+        Self::dir()
+            .map(|dir| dir.join(format!("{name}.{EXTENSION}")))
+            .ok_or_else(|| anyhow!("Could not find a config directory"))
+    }
+
+    /// Returns the names of the profiles in `dir`, sorted
+    pub fn list() -> Vec<String> {
+        let Some(entries) = Self::dir().and_then(|dir| fs::read_dir(dir).ok()) else {
+            return Vec::new();
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != EXTENSION {
+                    return None;
+                }
+                Some(path.file_stem()?.to_string_lossy().to_string())
+            })
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Loads the profile at `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        let str = fs::read_to_string(path)
+            .map_err(|err| anyhow!("Failed to read profile {}: {err}", path.display()))?;
+        serde_json::from_str(&str)
+            .map_err(|err| anyhow!("Invalid profile {}: {err}", path.display()))
+    }
+
+    /// Loads the profile `name`, see `path`, returning it along with where it was loaded from
+    pub fn load_named(name: &str) -> Result<(Self, PathBuf)> {
+        let path = Self::path(name)?;
+        if !path.exists() {
+            return Err(anyhow!(
+                "Profile {} does not exist, save one from the interface with 'P'",
+                path.display()
+            ));
+        }
+        Ok((Self::load(&path)?, path))
+    }
+
+    /// Saves which items in the library under `root` are enabled to the profile at `path`. Items
+    /// already in the profile that aren't in the library, such as those from a plugin that failed
+    /// to load, are kept as they were
+    pub fn save(root: &Category, path: &Path) -> Result<Self> {
+        // A profile that can't be read is an error rather than replaced, so it isn't lost
+        let mut profile = if path.exists() {
+            Self::load(path)?
+        } else {
+            Self::default()
+        };
+        for item in &root.items {
+            profile.record(item, "");
+        }
+        profile.saved = Utc::now().timestamp();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first so that a crash can't leave a half written profile
+        let temp = path.with_extension(format!("{EXTENSION}.tmp"));
+        fs::write(&temp, serde_json::to_string_pretty(&profile)?)?;
+        fs::rename(temp, path)?;
+        Ok(profile)
+    }
+
+    /// Records if `item`, inside the Category at `parent`, and everything in it is enabled
+    fn record(&mut self, item: &LibraryItem, parent: &str) {
+        match item {
+            LibraryItem::Document(doc) => {
+                self.documents.insert(doc.id().to_string(), doc.enabled);
+            }
+            LibraryItem::Category(cat) => {
+                let key = category_key(cat, parent);
+                self.categories.insert(key.clone(), cat.enabled);
+                let path = child_path(parent, cat.name());
+                if cat.single_selection() {
+                    match cat.items.iter().find(|item| item.enabled()) {
+                        Some(item) => self.choices.insert(key, item_key(item, &path)),
+                        None => self.choices.remove(&key),
+                    };
+                }
+                for item in &cat.items {
+                    self.record(item, &path);
+                }
+            }
+        }
+    }
+
+    /// Returns if `item`, inside the Category at `parent`, was enabled when the profile was saved,
+    /// `None` if the profile doesn't know about it
+    fn saved(&self, item: &LibraryItem, parent: &str) -> Option<bool> {
+        match item {
+            LibraryItem::Document(doc) => self.documents.get(doc.id()).copied(),
+            LibraryItem::Category(cat) => self.categories.get(&category_key(cat, parent)).copied(),
+        }
+    }
+
+    /// Enables or disables `item`, inside the Category at `parent`, and everything in it as they
+    /// were when the profile was saved. Anything the profile doesn't know about is set to
+    /// `new_items` and marked as new, except in single selection Categories where the profile
+    /// already chose another item. Returns how many new `Documents` there were
+    pub fn apply(&self, item: &mut LibraryItem, parent: &str, new_items: NewItems) -> usize {
+        let default = new_items == NewItems::Enabled;
+        match item {
+            LibraryItem::Document(doc) => {
+                let saved = self.documents.get(doc.id()).copied();
+                doc.enabled = saved.unwrap_or(default) && doc.can_download();
+                doc.set_new(saved.is_none());
+                usize::from(saved.is_none())
+            }
+            LibraryItem::Category(cat) => {
+                let key = category_key(cat, parent);
+                let saved = self.categories.get(&key).copied();
+                cat.enabled = saved.unwrap_or(default) && cat.can_download();
+                let path = child_path(parent, cat.name());
+                // The chosen item may not have loaded yet, so the choice is checked as well as the
+                // items that are here
+                let saved_enabled = cat
+                    .items
+                    .iter()
+                    .any(|item| self.saved(item, &path) == Some(true));
+                let chosen =
+                    cat.single_selection() && (self.choices.contains_key(&key) || saved_enabled);
+                cat.items
+                    .iter_mut()
+                    .map(|item| {
+                        let new_items = if chosen && self.saved(item, &path).is_none() {
+                            NewItems::Disabled
+                        } else {
+                            new_items
+                        };
+                        self.apply(item, &path, new_items)
+                    })
+                    .sum()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use apocalypse_library_types as sdk;
+
+    use super::*;
+
+    /// Returns an empty folder for the test called `name` to save profiles in
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("apocalypse_library_{}_{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn library() -> Category {
+        let doc = |id: &str, enabled: bool| {
+            sdk::Document::builder(id, format!("https://example.com/{id}"))
+                .id(id)
+                .enabled(enabled)
+                .build()
+        };
+        sdk::Category::builder("Root")
+            .item(doc("kept", true))
+            .item(doc("dropped", false))
+            .build()
+            .into()
+    }
+
+    #[test]
+    fn save_keeps_items_that_are_not_loaded() {
+        let dir = temp_dir("save_keeps");
+        let path = dir.join("profile.json");
+        let mut old = Profile::default();
+        old.documents.insert("missing".into(), true);
+        old.documents.insert("dropped".into(), true);
+        fs::write(&path, serde_json::to_string(&old).unwrap()).unwrap();
+
+        Profile::save(&library(), &path).unwrap();
+        let saved = Profile::load(&path).unwrap();
+        assert_eq!(saved.documents.get("missing"), Some(&true));
+        assert_eq!(saved.documents.get("kept"), Some(&true));
+        assert_eq!(saved.documents.get("dropped"), Some(&false));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn save_refuses_to_replace_an_unreadable_profile() {
+        let dir = temp_dir("save_refuses");
+        let path = dir.join("profile.json");
+        fs::write(&path, "not a profile").unwrap();
+
+        assert!(Profile::save(&library(), &path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a profile");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+use std::{fs, path::Path};
+
+use chrono::Utc;
+use minisign_verify::{PublicKey, Signature};
+use reqwest::{
+    blocking::Client,
+    header::{self, HeaderMap},
+    StatusCode, Url,
+};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::{cache, config::RemoteCatalog, parsing::LoadOptions, plugins::Format};
+
+/// A remote catalog as stored in the cache, along with what's needed to check if it has changed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedCatalog {
+    /// When the catalog was last fetched or confirmed unchanged, in seconds since the unix epoch
+    fetched: i64,
+    /// The `ETag` header the catalog was served with
+    etag: Option<String>,
+    /// The `Last-Modified` header the catalog was served with
+    last_modified: Option<String>,
+    /// How many seconds after `fetched` the catalog can be used without checking if it has
+    /// changed, from the `Cache-Control` header
+    max_age: Option<i64>,
+    /// The catalog itself
+    body: String,
+    /// The detached signature of the catalog, if it has a public key
+    signature: Option<String>,
+}
+
+/// A catalog that has been fetched and verified
+#[derive(Debug)]
+pub struct FetchedCatalog {
+    /// The catalog itself
+    pub body: Vec<u8>,
+    /// When the catalog was fetched, in seconds since the unix epoch. Not set for `file://` urls
+    pub fetched: Option<i64>,
+    /// If the catalog was loaded from the cache rather than the server
+    pub cached: bool,
+    /// A problem that didn't stop the catalog from being loaded
+    pub warning: Option<String>,
+}
+
+/// Returns the name of the cache file for the catalog at `url`
+fn cache_name(url: &str) -> String {
+    format!("catalog-{:x}.json", Sha1::digest(url.as_bytes()))
+}
+
+/// Returns the format of the catalog at `url` from the extension of its path, defaulting to JSON
+pub fn format(url: &str) -> Format {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| Format::from_path(Path::new(url.path())))
+        .unwrap_or(Format::Json)
+}
+
+/// Returns how many seconds a response can be reused for without checking with the server, from
+/// its `Cache-Control` header, and if it may be stored at all
+fn cache_control(headers: &HeaderMap) -> (Option<i64>, bool) {
+    let Some(value) = headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return (None, true);
+    };
+    let mut max_age = None;
+    let mut store = true;
+    for directive in value.split(',').map(str::trim) {
+        let directive = directive.to_ascii_lowercase();
+        if directive == "no-store" {
+            store = false;
+            max_age = Some(0);
+        } else if directive == "no-cache" {
+            max_age = Some(0);
+        } else if let Some(seconds) = directive.strip_prefix("max-age=") {
+            max_age = max_age.or_else(|| seconds.trim_matches('"').parse().ok());
+        }
+    }
+    (max_age, store)
+}
+
+/// Returns the value of the header called `name` as a string, if it is set
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(ToString::to_string)
+}
+
+/// Reads everything at `url`, which may be either a `file://` or `http(s)://` url
+fn read_url(client: &Client, url: &Url) -> Result<Vec<u8>, String> {
+    if url.scheme() == "file" {
+        let path = url
+            .to_file_path()
+            .map_err(|()| format!("{url} is not a valid file path"))?;
+        return fs::read(&path).map_err(|err| format!("failed to read {}: {err}", path.display()));
+    }
+    let response = client
+        .get(url.clone())
+        .send()
+        .map_err(|err| format!("failed to fetch {url}: {err}"))?;
+    if !response.status().is_success() {
+        return Err(format!("{url} returned {}", response.status()));
+    }
+    response
+        .bytes()
+        .map(|bytes| bytes.to_vec())
+        .map_err(|err| format!("failed to fetch {url}: {err}"))
+}
+
+/// Gets the detached signature for `catalog`, if it has a public key to check it with
+fn read_signature(client: &Client, catalog: &RemoteCatalog) -> Result<Option<String>, String> {
+    if catalog.public_key.is_none() {
+        return Ok(None);
+    }
+    let url = catalog
+        .signature_url
+        .clone()
+        .unwrap_or_else(|| format!("{}.minisig", catalog.url));
+    let url = Url::parse(&url).map_err(|err| format!("invalid signature url {url}: {err}"))?;
+    let signature = read_url(client, &url)?;
+    String::from_utf8(signature)
+        .map(Some)
+        .map_err(|_| "signature is not valid UTF-8".into())
+}
+
+/// Checks the `body` of `catalog` against its detached `signature`, if it has a public key
+fn verify(catalog: &RemoteCatalog, body: &[u8], signature: Option<&str>) -> Result<(), String> {
+    let Some(key) = &catalog.public_key else {
+        return Ok(());
+    };
+    let key = PublicKey::from_base64(key.trim())
+        .or_else(|_| PublicKey::decode(key))
+        .map_err(|err| format!("invalid public key: {err}"))?;
+    let signature = signature.ok_or("catalog has a public key but no signature")?;
+    let signature =
+        Signature::decode(signature).map_err(|err| format!("invalid signature: {err}"))?;
+    key.verify(body, &signature, false)
+        .map_err(|err| format!("signature check failed: {err}"))
+}
+
+/// Fetches the catalog from the server, only downloading it again if it has changed since the
+/// `cached` copy. Returns the catalog and if it may be cached
+fn fetch_http(
+    client: &Client,
+    catalog: &RemoteCatalog,
+    url: &Url,
+    cached: Option<&CachedCatalog>,
+) -> Result<(CachedCatalog, bool), String> {
+    let mut request = client.get(url.clone());
+    if let Some(cached) = cached {
+        if let Some(etag) = &cached.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let response = request
+        .send()
+        .map_err(|err| format!("failed to fetch: {err}"))?;
+    let headers = response.headers().clone();
+    let (max_age, store) = cache_control(&headers);
+    let etag = header_str(&headers, header::ETAG);
+    let last_modified = header_str(&headers, header::LAST_MODIFIED);
+    let fetched = Utc::now().timestamp();
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some(cached) = cached {
+            let catalog = CachedCatalog {
+                fetched,
+                etag: etag.or_else(|| cached.etag.clone()),
+                last_modified: last_modified.or_else(|| cached.last_modified.clone()),
+                max_age,
+                ..cached.clone()
+            };
+            return Ok((catalog, store));
+        }
+    }
+    if !response.status().is_success() {
+        return Err(format!("server returned {}", response.status()));
+    }
+    let body = response
+        .bytes()
+        .map_err(|err| format!("failed to fetch: {err}"))?;
+    let body = String::from_utf8(body.to_vec()).map_err(|_| "catalog is not valid UTF-8")?;
+    let signature = read_signature(client, catalog)?;
+    let catalog = CachedCatalog {
+        fetched,
+        etag,
+        last_modified,
+        max_age,
+        body,
+        signature,
+    };
+    Ok((catalog, store))
+}
+
+/// Fetches `catalog` and checks its signature. HTTP catalogs are cached, and only fetched again
+/// once their `Cache-Control` max age has passed, or they are always checked for changes if they
+/// don't have one. `options.refresh` and `options.offline` work the same way as for plugins. If
+/// the catalog can't be fetched any cached copy is used instead
+pub fn fetch(catalog: &RemoteCatalog, options: &LoadOptions) -> Result<FetchedCatalog, String> {
+    let url = Url::parse(&catalog.url).map_err(|err| format!("invalid url: {err}"))?;
+    let client = Client::builder()
+        .timeout(options.timeout)
+        .build()
+        .map_err(|err| err.to_string())?;
+    if url.scheme() == "file" {
+        let body = read_url(&client, &url)?;
+        let signature = read_signature(&client, catalog)?;
+        verify(catalog, &body, signature.as_deref())?;
+        return Ok(FetchedCatalog {
+            body,
+            fetched: None,
+            cached: false,
+            warning: None,
+        });
+    }
+
+    let name = cache_name(&catalog.url);
+    let cached: Option<CachedCatalog> = cache::load(&name);
+    let use_cached = |cached: CachedCatalog, warning: Option<String>| {
+        verify(catalog, cached.body.as_bytes(), cached.signature.as_deref())?;
+        Ok(FetchedCatalog {
+            body: cached.body.into_bytes(),
+            fetched: Some(cached.fetched),
+            cached: true,
+            warning,
+        })
+    };
+    if let Some(cached) = &cached {
+        let now = Utc::now().timestamp();
+        let fresh = cached
+            .max_age
+            .is_some_and(|max_age| now - cached.fetched < max_age);
+        if options.offline || (!options.refresh && fresh) {
+            return use_cached(cached.clone(), None);
+        }
+    }
+    if options.offline {
+        return Err("no cached copy to use while offline".into());
+    }
+
+    let result = fetch_http(&client, catalog, &url, cached.as_ref()).and_then(|(new, store)| {
+        verify(catalog, new.body.as_bytes(), new.signature.as_deref())?;
+        Ok((new, store))
+    });
+    match result {
+        Ok((new, store)) => {
+            let mut warning = None;
+            if store {
+                if let Err(err) = cache::save(&name, &new) {
+                    warning = Some(format!("failed to cache catalog: {err}"));
+                }
+            }
+            Ok(FetchedCatalog {
+                body: new.body.into_bytes(),
+                fetched: Some(new.fetched),
+                cached: false,
+                warning,
+            })
+        }
+        // Stale data is better than no data
+        Err(err) => match cached {
+            Some(cached) => use_cached(cached, Some(format!("{err}, using cached copy instead"))),
+            None => Err(err),
+        },
+    }
+}
@@ -0,0 +1,428 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    path::Path,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use apocalypse_library_types::{http, Document, DownloadType, LibraryItem, VersionRecord};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use reqwest::{blocking::Client, Url};
+use scraper::{Html, Selector};
+use serde::Deserialize;
+
+/// The file name suffix of declarative source files
+pub const SOURCE_SUFFIX: &str = ".source.toml";
+
+/// The `category` template used when a source doesn't have one
+#[allow(clippy::literal_string_with_formatting_args)]
+const DEFAULT_CATEGORY: &str = "{source}/{dir}";
+
+/// The most index pages a source may fetch, this also stops link loops between pages
+const MAX_PAGES: usize = 1000;
+
+/// How many `HEAD` requests are made at once when getting sizes
+const HEAD_THREADS: usize = 8;
+
+/// Where the size of each download comes from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SizeFrom {
+    /// The `size` capture group of the `links` pattern if it has one, otherwise a `HEAD` request
+    #[default]
+    Auto,
+    /// The `size` capture group of the `links` pattern, such as the size column of a file listing
+    Listing,
+    /// The `Content-Length` of a `HEAD` request to each download
+    Head,
+    /// Sizes are left as zero
+    None,
+}
+
+/// A rule for finding links on a page, with either a regex `pattern` or a CSS `selector`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    /// A regex matched against the page. The link is the `url` capture group, or else the first
+    /// capture group, or else the whole match. Every capture group can be used in templates
+    #[serde(default)]
+    pattern: Option<String>,
+    /// A CSS selector for the elements to take links from, such as `a[href$=".zim"]`
+    #[serde(default)]
+    selector: Option<String>,
+    /// The attribute of the selected elements that holds the link
+    #[serde(default = "default_attribute")]
+    attribute: String,
+}
+
+fn default_attribute() -> String {
+    "href".into()
+}
+
+/// A compiled `Rule`
+enum Matcher {
+    Pattern(Regex),
+    Selector(Selector, String),
+}
+
+impl Matcher {
+    fn new(rule: &Rule) -> Result<Self, String> {
+        match (&rule.pattern, &rule.selector) {
+            (Some(pattern), None) => Regex::new(pattern)
+                .map(Self::Pattern)
+                .map_err(|err| format!("invalid pattern: {err}")),
+            (None, Some(selector)) => Selector::parse(selector)
+                .map(|selector| Self::Selector(selector, rule.attribute.clone()))
+                .map_err(|err| format!("invalid selector {selector}: {err}")),
+            _ => Err("each rule needs either a `pattern` or a `selector`".into()),
+        }
+    }
+
+    /// Returns every link on `page`, with the template variables captured along with it
+    fn find(&self, page: &str) -> Vec<(String, HashMap<String, String>)> {
+        match self {
+            Self::Pattern(regex) => regex
+                .captures_iter(page)
+                .filter_map(|captures| {
+                    let mut vars = HashMap::new();
+                    for (i, name) in regex.capture_names().enumerate().skip(1) {
+                        if let Some(capture) = captures.get(i) {
+                            vars.insert(i.to_string(), capture.as_str().to_string());
+                            if let Some(name) = name {
+                                vars.insert(name.to_string(), capture.as_str().to_string());
+                            }
+                        }
+                    }
+                    let link = captures
+                        .name("url")
+                        .or_else(|| captures.get(1))
+                        .or_else(|| captures.get(0))?;
+                    Some((decode_html(link.as_str()), vars))
+                })
+                .collect(),
+            Self::Selector(selector, attribute) => Html::parse_document(page)
+                .select(selector)
+                .filter_map(|element| {
+                    let link = element.value().attr(attribute)?.to_string();
+                    let text = element.text().collect::<String>().trim().to_string();
+                    Some((link, HashMap::from([("text".to_string(), text)])))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A declarative description of how to scrape a site into items, read from a `.source.toml` file.
+/// The `name`, `url` and `category` templates can use `{url}`, `{path}` (relative to `start_url`),
+/// `{file}`, `{stem}`, `{dir}` (the folder the link was found in), `{source}` (the file name
+/// without `.source.toml`), `{text}` for selectors, and the capture groups of a `links` pattern by
+/// number or name
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Source {
+    /// The index page to start from
+    start_url: String,
+    /// How many levels of linked index pages to follow from the start page
+    #[serde(default)]
+    depth: usize,
+    /// Finds the downloads on each page
+    links: Rule,
+    /// Finds the index pages to follow, defaults to links to sub folders of the current page
+    #[serde(default)]
+    follow: Option<Rule>,
+    /// A template for the name of each item
+    #[serde(default = "default_name")]
+    name: String,
+    /// A template for the url each item is downloaded from
+    #[serde(default = "default_url")]
+    url: String,
+    /// A template for the Categories each item is placed in, separated by `/`. Defaults to the
+    /// name of the source file followed by the folder the item was found in
+    #[serde(default)]
+    category: Option<String>,
+    /// Which levels of `category` only allow a single selection
+    #[serde(default)]
+    single_selection: Vec<bool>,
+    /// How the items are downloaded
+    #[serde(default = "default_download_type")]
+    download_type: DownloadType,
+    /// Where the size of each item comes from
+    #[serde(default)]
+    size: SizeFrom,
+    /// If the items start out enabled, the same as the `enabled` of a Document
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+}
+
+fn default_name() -> String {
+    "{file}".into()
+}
+
+fn default_url() -> String {
+    "{url}".into()
+}
+
+const fn default_download_type() -> DownloadType {
+    DownloadType::Http
+}
+
+const fn default_enabled() -> bool {
+    true
+}
+
+/// A download found while scraping
+struct Found {
+    url: Url,
+    vars: HashMap<String, String>,
+    size: Option<u64>,
+}
+
+/// Replaces every `{name}` in `template` with its value from `vars`, unknown names are left empty
+fn fill(template: &str, vars: &HashMap<String, String>) -> String {
+    let mut filled = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        filled.push_str(&rest[..start]);
+        if let Some(value) = vars.get(&rest[start + 1..start + end]) {
+            filled.push_str(value);
+        }
+        rest = &rest[start + end + 1..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// Decodes the few HTML entities that show up in links, such as `&amp;`
+fn decode_html(str: &str) -> String {
+    str.replace("&amp;", "&")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+}
+
+/// Decodes the `%20` style escapes in part of a url
+fn decode_url(str: &str) -> String {
+    percent_decode_str(str).decode_utf8_lossy().into_owned()
+}
+
+/// Parses a size such as `1234`, `12M`, `1.5 GiB` or `700kB`. Units are treated as powers of 1024,
+/// as file listings use them
+fn parse_size(str: &str) -> Option<u64> {
+    let str = str.trim();
+    let split = str
+        .find(|char: char| !(char.is_ascii_digit() || char == '.' || char == ','))
+        .unwrap_or(str.len());
+    let (number, unit) = str.split_at(split);
+    let number: f64 = number.replace(',', "").parse().ok()?;
+    let power = match unit
+        .trim()
+        .chars()
+        .next()
+        .map(|char| char.to_ascii_lowercase())
+    {
+        None | Some('b') => 0,
+        Some('k') => 1,
+        Some('m') => 2,
+        Some('g') => 3,
+        Some('t') => 4,
+        _ => return None,
+    };
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Some((number * 1024f64.powi(power)) as u64)
+}
+
+/// Returns the path of `url` relative to `base`, decoded, if `url` is inside `base`
+fn relative(base: &Url, url: &Url) -> Option<String> {
+    url.as_str()
+        .strip_prefix(base.as_str())
+        .map(|path| decode_url(path.split(['?', '#']).next().unwrap_or_default()))
+}
+
+/// Fetches `url` as text, failing if the `deadline` has passed
+fn get(client: &Client, url: &Url, deadline: Instant) -> Result<String, String> {
+    if Instant::now() >= deadline {
+        return Err("ran out of time".into());
+    }
+    let response = client
+        .get(url.clone())
+        .send()
+        .map_err(|err| format!("failed to fetch {url}: {err}"))?;
+    if !response.status().is_success() {
+        return Err(format!("{url} returned {}", response.status()));
+    }
+    response
+        .text()
+        .map_err(|err| format!("failed to fetch {url}: {err}"))
+}
+
+/// Fills in the size of each of `found` that doesn't have one with a `HEAD` request
+fn head_sizes(client: &Client, found: &mut [Found], deadline: Instant) {
+    let missing: Vec<&mut Found> = found
+        .iter_mut()
+        .filter(|found| found.size.is_none())
+        .collect();
+    let missing = Mutex::new(missing);
+    thread::scope(|scope| {
+        for _ in 0..HEAD_THREADS {
+            scope.spawn(|| {
+                while let Some(found) = missing.lock().ok().and_then(|mut missing| missing.pop()) {
+                    if Instant::now() >= deadline {
+                        break;
+                    }
+                    found.size = http::head_size(client, found.url.as_str());
+                }
+            });
+        }
+    });
+}
+
+/// Fetches every page of `source` from `start`, following links up to its `depth`, and returns
+/// the downloads found on them
+fn crawl(
+    source: &Source,
+    start: &Url,
+    client: &Client,
+    deadline: Instant,
+) -> Result<Vec<Found>, String> {
+    let links = Matcher::new(&source.links).map_err(|err| format!("links: {err}"))?;
+    let follow = source
+        .follow
+        .as_ref()
+        .map(|rule| Matcher::new(rule).map_err(|err| format!("follow: {err}")))
+        .transpose()?;
+    // Index pages link to their sub folders with a trailing slash
+    let sub_folders = Matcher::Selector(
+        Selector::parse("a[href$='/']").map_err(|err| err.to_string())?,
+        default_attribute(),
+    );
+
+    let mut found: Vec<Found> = Vec::new();
+    let mut seen = HashSet::new();
+    let mut visited = HashSet::from([start.clone()]);
+    let mut queue = VecDeque::from([(start.clone(), 0)]);
+    while let Some((page_url, level)) = queue.pop_front() {
+        let page = get(client, &page_url, deadline)?;
+        let dir = relative(start, &page_url).unwrap_or_default();
+        for (link, mut vars) in links.find(&page) {
+            let Ok(url) = page_url.join(&link) else {
+                continue;
+            };
+            if seen.insert(url.clone()) {
+                let size = vars.get("size").and_then(|size| parse_size(size));
+                vars.insert("dir".into(), dir.trim_end_matches('/').into());
+                found.push(Found { url, vars, size });
+            }
+        }
+        if level >= source.depth {
+            continue;
+        }
+        let next = follow.as_ref().unwrap_or(&sub_folders).find(&page);
+        for (link, _) in next {
+            let Ok(url) = page_url.join(&link) else {
+                continue;
+            };
+            // Never wander off the site being scraped, or back up to a parent folder
+            let inside = if follow.is_some() { start } else { &page_url };
+            if url.as_str().len() > inside.as_str().len()
+                && url.as_str().starts_with(inside.as_str())
+                && visited.insert(url.clone())
+            {
+                if visited.len() > MAX_PAGES {
+                    return Err(format!("followed more than {MAX_PAGES} pages"));
+                }
+                queue.push_back((url, level + 1));
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Builds the item for a download `found` by `source`
+fn item(source: &Source, source_name: &str, start: &Url, found: Found) -> LibraryItem {
+    let Found {
+        url,
+        mut vars,
+        size,
+    } = found;
+    let path = relative(start, &url).unwrap_or_else(|| url.path().to_string());
+    let file = decode_url(
+        url.path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .unwrap_or_default(),
+    );
+    let stem = Path::new(&file)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    vars.insert("url".into(), url.to_string());
+    vars.insert("path".into(), path);
+    vars.insert("file".into(), file);
+    vars.insert("stem".into(), stem);
+    vars.insert("source".into(), source_name.to_string());
+
+    let category = source.category.as_deref().unwrap_or(DEFAULT_CATEGORY);
+    // Empty variables such as `{dir}` on the start page would leave empty levels behind
+    let path = fill(category, &vars)
+        .split('/')
+        .filter(|level| !level.is_empty())
+        .collect::<Vec<_>>()
+        .join("/");
+    let mut doc = Document::builder(fill(&source.name, &vars), fill(&source.url, &vars))
+        .size(size.unwrap_or(0))
+        .download_type(source.download_type)
+        .single_selection(source.single_selection.clone())
+        .enabled(source.enabled);
+    if !path.is_empty() {
+        doc = doc.path(path);
+    }
+    doc.build().into()
+}
+
+/// Scrapes the site described by the source file at `path`, returning the items found as JSON
+/// lines, the same as a plugin's output. Gives up once `timeout` has passed
+pub fn run(path: &Path, timeout: Duration) -> Result<Vec<u8>, String> {
+    let deadline = Instant::now() + timeout;
+    let text = fs::read_to_string(path).map_err(|err| format!("failed to read: {err}"))?;
+    let source: Source = toml::from_str(&text).map_err(|err| format!("invalid source: {err}"))?;
+    let start = Url::parse(&source.start_url).map_err(|err| format!("invalid start_url: {err}"))?;
+    let client = Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(|err| err.to_string())?;
+
+    let mut found = crawl(&source, &start, &client, deadline)?;
+    match source.size {
+        SizeFrom::Auto => head_sizes(&client, &mut found, deadline),
+        SizeFrom::Head => {
+            for found in &mut found {
+                found.size = None;
+            }
+            head_sizes(&client, &mut found, deadline);
+        }
+        SizeFrom::Listing | SizeFrom::None => {}
+    }
+
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let source_name = file_name.strip_suffix(SOURCE_SUFFIX).unwrap_or(&file_name);
+    let mut output = VersionRecord::current().to_json_line().into_bytes();
+    output.push(b'\n');
+    for found in found {
+        output.extend_from_slice(
+            item(&source, source_name, &start, found)
+                .to_json_line()
+                .as_bytes(),
+        );
+        output.push(b'\n');
+    }
+    Ok(output)
+}
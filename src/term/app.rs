@@ -1,4 +1,27 @@
-use crate::types::{Category, LibraryItem};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::mpsc::{self, TryRecvError},
+    thread,
+};
+
+use humansize::WINDOWS;
+
+use crate::{
+    budget, cache,
+    download::{self, RsyncJob},
+    parsing::{Diagnostic, LoadEvent, LoadReport, BUILTIN},
+    profile::{NewItems, Profile},
+    term::ui::StatefulListCounter,
+    trust::{ApprovalRequest, TrustStore},
+    types::{Category, LibraryItem, Requirement, SizeStats},
+};
+
+/// The name of the cache file that rsync dry run sizes are stored in
+const SIZE_CACHE: &str = "rsync_sizes.json";
+/// The most plugin load events to handle in a single tick, so that the interface stays responsive
+/// while large plugins are loading
+const LOAD_EVENTS_PER_TICK: usize = 2000;
 
 #[derive(Debug, Clone, Copy)]
 pub enum SortStyle {
@@ -6,17 +29,99 @@ pub enum SortStyle {
     Size,
 }
 
+/// Tracks the progress of refreshing rsync sizes in the background
+#[derive(Debug)]
+pub struct SizeRefresh {
+    /// Receives the results for each `download::size_key` as they complete
+    receiver: mpsc::Receiver<(String, Result<SizeStats, String>)>,
+    /// How many `Documents` have been checked so far
+    pub done: usize,
+    /// How many `Documents` are being checked
+    pub total: usize,
+    /// How many checks have failed
+    pub failed: usize,
+}
+
+/// The popup for loading and saving selection profiles
+#[derive(Debug)]
+pub struct ProfileMenu {
+    /// The names of the saved profiles
+    pub names: Vec<String>,
+    /// Which profile is highlighted
+    pub counter: StatefulListCounter,
+    /// The name being typed for a new profile, while one is being saved
+    pub naming: Option<String>,
+}
+
 #[derive(Debug)]
+// Each popup has its own flag, they are checked in order of priority when handling keys
+#[allow(clippy::struct_excessive_bools)]
 pub struct App {
     pub should_quit: bool,
     pub category: Category,
     pub depth: usize,
     pub download: bool,
     sort_style: SortStyle,
+    /// Receives the items from the plugins as they load, until they have all finished
+    loader: Option<mpsc::Receiver<LoadEvent>>,
+    /// The names of the plugins that are still loading
+    pub loading: Vec<String>,
+    /// The number of ticks so far, used for animations
+    pub ticks: usize,
+    /// The results of loading the library from the plugins
+    pub report: LoadReport,
+    /// Should the plugin load report be shown
+    pub show_report: bool,
+    /// Should the details of the highlighted item be shown
+    pub show_details: bool,
+    /// How many rows the open popup is scrolled down, limited to its length when it is rendered
+    pub scroll: u16,
+    /// Tells the user what else changed because of their last action, such as requirements that
+    /// were enabled along with an item
+    pub notice: Option<String>,
+    /// The profile being used, and where it was loaded from. Items are enabled as it says as
+    /// they load
+    pub profile: Option<(PathBuf, Profile)>,
+    /// If items that aren't in the profile are enabled
+    new_items: NewItems,
+    /// The profile popup, if it is open
+    pub profiles: Option<ProfileMenu>,
+    /// The capacity being typed into the fit to budget popup, if it is open
+    pub budget: Option<String>,
+    /// The capacity the selection was last fit to, the budget popup starts with it
+    last_budget: String,
+    /// A capacity to fit the selection to once the plugins have finished loading
+    pending_budget: Option<String>,
+    /// Executable plugins waiting for the user to approve them, the first is being asked about
+    pub approvals: VecDeque<ApprovalRequest>,
+    /// Where the library is downloaded to
+    out_path: String,
+    /// Settings that control how items are downloaded
+    download_options: download::Options,
+    /// The plugin and name of every `Document` loaded so far, by id, to find the same item coming
+    /// from more than one plugin
+    loaded: HashMap<String, (String, String)>,
+    /// Documents that aren't shown because another plugin provides the same id, both built-ins
+    /// that a plugin replaced and later copies of an item. They are shown again if the plugin
+    /// hiding them is discarded
+    hidden: Vec<LibraryItem>,
+    /// Cached rsync dry run sizes, keyed by `download::size_key`
+    size_cache: HashMap<String, SizeStats>,
+    /// The rsync size refresh currently running, if there is one
+    pub size_refresh: Option<SizeRefresh>,
 }
 
 impl App {
-    pub fn new(mut category: Category) -> Self {
+    pub fn new(
+        mut category: Category,
+        loader: mpsc::Receiver<LoadEvent>,
+        out_path: String,
+        download_options: download::Options,
+        new_items: NewItems,
+    ) -> Self {
+        // Sizes from any previous refresh, these are applied to the items as they load
+        let size_cache: HashMap<String, SizeStats> = cache::load(SIZE_CACHE).unwrap_or_default();
+
         category.sort(SortStyle::Alphabetical);
         category.items.iter_mut().for_each(|item| match item {
             LibraryItem::Document(_) => {}
@@ -28,7 +133,61 @@ impl App {
             depth: 0,
             download: false,
             sort_style: SortStyle::Alphabetical,
+            loader: Some(loader),
+            loading: Vec::new(),
+            ticks: 0,
+            report: LoadReport::default(),
+            show_report: false,
+            show_details: false,
+            scroll: 0,
+            notice: None,
+            profile: None,
+            new_items,
+            profiles: None,
+            budget: None,
+            last_budget: String::new(),
+            pending_budget: None,
+            approvals: VecDeque::new(),
+            out_path,
+            download_options,
+            loaded: HashMap::new(),
+            hidden: Vec::new(),
+            size_cache,
+            size_refresh: None,
+        }
+    }
+
+    /// Starts gathering accurate sizes for every enabled rsync `Document` with rsync dry runs. This
+    /// runs in the background, and the results are applied as they come in on each tick
+    pub fn refresh_sizes(&mut self) {
+        if self.size_refresh.is_some() {
+            return;
+        }
+        let mut jobs: Vec<RsyncJob> = Vec::new();
+        self.category.items.iter().for_each(|item| {
+            download::collect_rsync_jobs(&self.out_path, item, &self.download_options, &mut jobs);
+        });
+        if jobs.is_empty() {
+            return;
         }
+
+        let (sender, receiver) = mpsc::channel();
+        let total = jobs.len();
+        thread::spawn(move || {
+            for job in jobs {
+                let result = download::rsync_stats(&job).map_err(|err| err.to_string());
+                if sender.send((job.key, result)).is_err() {
+                    // The app has closed, no point continuing
+                    break;
+                }
+            }
+        });
+        self.size_refresh = Some(SizeRefresh {
+            receiver,
+            done: 0,
+            total,
+            failed: 0,
+        });
     }
 
     pub fn sort(&mut self) {
@@ -36,9 +195,8 @@ impl App {
         let (cat, _) = self.get_selected_category();
         cat.sort(style);
         let index = cat.counter.selected();
-        match &mut cat.items[index] {
-            LibraryItem::Document(_) => {}
-            LibraryItem::Category(cat) => cat.sort(style),
+        if let Some(LibraryItem::Category(cat)) = cat.items.get_mut(index) {
+            cat.sort(style);
         }
     }
 
@@ -50,6 +208,22 @@ impl App {
         self.sort();
     }
 
+    /// Returns the highlighted item, along with where it will be downloaded to
+    pub fn highlighted(&self) -> Option<(&LibraryItem, String)> {
+        let (categories, item) = self.category.highlighted(self.depth)?;
+        let folder = std::iter::once(self.out_path.as_str())
+            .chain(categories)
+            .collect::<Vec<_>>()
+            .join("/");
+        let destination = match item {
+            LibraryItem::Document(doc) => {
+                download::destination(&folder, doc, &self.download_options)
+            }
+            LibraryItem::Category(cat) => format!("{folder}/{}", cat.name()),
+        };
+        Some((item, destination))
+    }
+
     pub fn get_selected_category(&mut self) -> (&mut Category, usize) {
         self.category.get_selected_category(self.depth)
     }
@@ -111,7 +285,7 @@ impl App {
         let (cat, depth) = self.get_selected_category();
         if !cat.is_selected_last() || depth == 0 {
             let max = cat.counter.size();
-            cat.counter.set_selected(max - 1);
+            cat.counter.set_selected(max.saturating_sub(1));
         } else {
             let index = cat.counter.selected();
             match &mut cat.items[index] {
@@ -126,7 +300,7 @@ impl App {
 
     pub fn toggle(&mut self) {
         let result = self.get_selected_category();
-        match result {
+        let requirements = match result {
             (cat, 0) => cat.toggle_selected_item(),
             (cat, _) => {
                 let index = cat.counter.selected();
@@ -135,12 +309,13 @@ impl App {
                     LibraryItem::Category(cat) => cat.toggle_selected_item(),
                 }
             }
-        }
+        };
+        self.enable_requirements(requirements);
     }
 
     pub fn toggle_all(&mut self) {
         let result = self.get_selected_category();
-        match result {
+        let requirements = match result {
             (cat, 0) => cat.toggle_all_items(),
             (cat, _) => {
                 let index = cat.counter.selected();
@@ -149,13 +324,505 @@ impl App {
                     LibraryItem::Category(cat) => cat.toggle_all_items(),
                 }
             }
+        };
+        self.enable_requirements(requirements);
+    }
+
+    /// Enables the items that `requirements` refer to, wherever they are in the library, along
+    /// with anything those require in turn. What was pulled in, switched off to make way for it,
+    /// or couldn't be found, is shown in the `notice`. Items that are no longer required stop
+    /// being marked as required
+    fn enable_requirements(&mut self, mut requirements: Vec<Requirement>) {
+        let mut enabled = Vec::new();
+        let mut disabled = Vec::new();
+        let mut missing = Vec::new();
+        let mut seen = HashSet::new();
+        // Reverse so that requirements are handled in the order they were declared
+        requirements.reverse();
+        while let Some(requirement) = requirements.pop() {
+            if !seen.insert(requirement.id.clone()) {
+                continue;
+            }
+            let Requirement { id, required_by } = requirement;
+            match self
+                .category
+                .enable_required(&id, &required_by, &mut disabled)
+            {
+                None => missing.push(format!("'{id}' (required by {required_by})")),
+                Some(false) => {}
+                Some(true) => {
+                    let Some(doc) = self.category.find_document(&id) else {
+                        continue;
+                    };
+                    enabled.push(format!("{} (required by {required_by})", doc.name()));
+                    requirements.extend(doc.requires().iter().rev().map(|id| Requirement {
+                        id: id.clone(),
+                        required_by: doc.name().to_string(),
+                    }));
+                }
+            }
+        }
+
+        self.category.refresh_required_by();
+
+        let mut notice = Vec::new();
+        if !enabled.is_empty() {
+            notice.push(format!("Also enabled {}", enabled.join(", ")));
+        }
+        if !disabled.is_empty() {
+            notice.push(format!(
+                "Disabled {} as only one item can be chosen in their category",
+                disabled.join(", ")
+            ));
+        }
+        if !missing.is_empty() {
+            notice.push(format!("Not in the library: {}", missing.join(", ")));
+        }
+        self.notice = (!notice.is_empty()).then(|| notice.join(" | "));
+    }
+
+    pub fn tick(&mut self) {
+        self.ticks = self.ticks.wrapping_add(1);
+        self.apply_load_events();
+        self.apply_size_refresh();
+    }
+
+    /// Returns if the plugins are still loading
+    pub const fn is_loading(&self) -> bool {
+        self.loader.is_some()
+    }
+
+    /// Adds any items that have been loaded from the plugins since the last tick
+    fn apply_load_events(&mut self) {
+        let Some(loader) = &self.loader else {
+            return;
+        };
+        let mut changed = false;
+        let mut finished = false;
+        for _ in 0..LOAD_EVENTS_PER_TICK {
+            match loader.try_recv() {
+                Ok(LoadEvent::Started(name)) => self.loading.push(name),
+                Ok(LoadEvent::Item(mut item)) => {
+                    match &self.profile {
+                        Some((_, profile)) => {
+                            profile.apply(&mut item, "", self.new_items);
+                        }
+                        None => item.set_enabled_recursive(),
+                    }
+                    item.for_each_document_mut(&mut |doc| {
+                        if let Some(stats) = self.size_cache.get(&download::size_key(doc)) {
+                            doc.set_size_stats(*stats);
+                        }
+                    });
+                    let replaced = self.category.take_replaced(&item);
+                    self.hidden.extend(replaced);
+                    let diagnostics = &mut self.report.diagnostics;
+                    let (item, duplicates) =
+                        collapse_duplicates(&mut self.loaded, diagnostics, item);
+                    self.hidden.extend(duplicates);
+                    if let Some(item) = item {
+                        self.category.add(item);
+                    }
+                    changed = true;
+                }
+                Ok(LoadEvent::Diagnostic(diagnostic)) => self.report.diagnostics.push(diagnostic),
+                Ok(LoadEvent::Discard(name)) => {
+                    self.category.remove_source(&name);
+                    self.loaded.retain(|_, (source, _)| *source != name);
+                    restore_hidden(
+                        &mut self.category,
+                        &mut self.loaded,
+                        &mut self.hidden,
+                        &name,
+                    );
+                    changed = true;
+                }
+                Ok(LoadEvent::Approval(request)) => self.approvals.push_back(request),
+                Ok(LoadEvent::Finished(plugin)) => {
+                    self.loading.retain(|name| *name != plugin.name);
+                    // Keep the reports in a consistent order, regardless of which finished first
+                    let index = self
+                        .report
+                        .plugins
+                        .partition_point(|report| report.name < plugin.name);
+                    self.report.plugins.insert(index, plugin);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    finished = true;
+                    break;
+                }
+            }
+        }
+        if changed {
+            self.category.update_counters();
+            self.sort();
+        }
+        if finished {
+            self.loader = None;
+            self.loading.clear();
+            if let Some(budget) = self.pending_budget.take() {
+                self.fit_to_budget(&budget);
+            }
+        }
+    }
+
+    /// Applies any rsync sizes that have been gathered since the last tick
+    fn apply_size_refresh(&mut self) {
+        let Some(refresh) = &mut self.size_refresh else {
+            return;
+        };
+        let mut updated = false;
+        while let Ok((key, result)) = refresh.receiver.try_recv() {
+            refresh.done += 1;
+            match result {
+                Ok(stats) => {
+                    self.category.for_each_document_mut(&mut |doc| {
+                        if download::size_key(doc) == key {
+                            doc.set_size_stats(stats);
+                        }
+                    });
+                    self.size_cache.insert(key, stats);
+                    updated = true;
+                }
+                Err(_) => refresh.failed += 1,
+            }
+        }
+        if refresh.done >= refresh.total {
+            self.size_refresh = None;
+        }
+        if updated {
+            if !self.is_loading() {
+                // Sizes for urls or filters that are no longer in the library would never be used
+                let mut keys = HashSet::new();
+                for item in &self.category.items {
+                    item.for_each_document(&mut |doc| {
+                        keys.insert(download::size_key(doc));
+                    });
+                }
+                self.size_cache.retain(|key, _| keys.contains(key));
+            }
+            // Not being able to cache the sizes shouldn't interrupt anything, they just wont be
+            // remembered next time
+            let _ = cache::save(SIZE_CACHE, &self.size_cache);
+        }
+    }
+
+    /// Uses the `profile` loaded from `path`, enabling the items already loaded as it says, along
+    /// with those that load later. Returns how many items it didn't know about
+    pub fn use_profile(&mut self, path: PathBuf, profile: Profile) -> usize {
+        let new = self
+            .category
+            .items
+            .iter_mut()
+            .map(|item| profile.apply(item, "", self.new_items))
+            .sum();
+        self.profile = Some((path, profile));
+        new
+    }
+
+    /// Returns the name of the profile being used, if there is one
+    pub fn profile_name(&self) -> Option<String> {
+        let (path, _) = self.profile.as_ref()?;
+        Some(path.file_stem()?.to_string_lossy().to_string())
+    }
+
+    /// Opens the profile popup, listing the saved profiles
+    pub fn open_profiles(&mut self) {
+        let mut names = Profile::list();
+        let mut current = self.profile_name();
+        if let Some((path, _)) = &self.profile {
+            // A profile loaded from elsewhere with `--profile` is listed by its path
+            if Profile::dir().as_deref() != path.parent() {
+                current = Some(path.display().to_string());
+                names.insert(0, path.display().to_string());
+            }
+        }
+        let mut counter = StatefulListCounter::new(names.len());
+        // Start on the profile being used
+        if let Some(index) = names.iter().position(|name| Some(name) == current.as_ref()) {
+            counter.set_selected(index);
+        }
+        self.profiles = Some(ProfileMenu {
+            names,
+            counter,
+            naming: None,
+        });
+    }
+
+    /// Loads the saved profile called `name` and closes the profile popup
+    pub fn load_profile(&mut self, name: &str) {
+        self.profiles = None;
+        self.notice = Some(match Profile::load_named(name) {
+            Ok((profile, path)) => {
+                let new = self.use_profile(path, profile);
+                format!("Loaded profile {name}, {new} new item(s) are highlighted")
+            }
+            Err(err) => format!("Failed to load profile {name}: {err}"),
+        });
+    }
+
+    /// Saves which items are enabled as the profile called `name`, and closes the profile popup.
+    /// Nothing is new any more once it is saved
+    pub fn save_profile(&mut self, name: &str) {
+        self.profiles = None;
+        let saved =
+            Profile::path(name).and_then(|path| Ok((Profile::save(&self.category, &path)?, path)));
+        self.notice = Some(match saved {
+            Ok((profile, path)) => {
+                self.category
+                    .for_each_document_mut(&mut |doc| doc.set_new(false));
+                self.profile = Some((path, profile));
+                format!("Saved profile {name}")
+            }
+            Err(err) => format!("Failed to save profile {name}: {err}"),
+        });
+    }
+
+    /// Fits the selection to `budget` once the plugins have finished loading, so that everything
+    /// they provide is considered
+    pub fn fit_when_loaded(&mut self, budget: String) {
+        self.last_budget.clone_from(&budget);
+        if self.is_loading() {
+            self.pending_budget = Some(budget);
+        } else {
+            self.fit_to_budget(&budget);
+        }
+    }
+
+    /// Opens the fit to budget popup, starting with the last capacity used
+    pub fn open_budget(&mut self) {
+        self.budget = Some(self.last_budget.clone());
+    }
+
+    /// Replaces the selection with the most important items that fit in `budget`, a capacity
+    /// such as `256GB`, and closes the budget popup. What was picked is shown in the `notice`, so
+    /// the user can adjust it before downloading
+    pub fn fit_to_budget(&mut self, budget: &str) {
+        self.budget = None;
+        let budget = budget.trim();
+        let Some(capacity) = budget::parse_capacity(budget) else {
+            self.notice = Some(format!(
+                "'{budget}' is not a size, try one such as 256GB or 1TB"
+            ));
+            return;
+        };
+        self.last_budget = budget.to_string();
+        let fit = budget::fit(&mut self.category, capacity);
+        let used = humansize::format_size(fit.used, WINDOWS);
+        let left = humansize::format_size(capacity - fit.used, WINDOWS);
+        let mut notice = format!(
+            "Fit to {budget}: picked {} item(s) using {used}, {left} left",
+            fit.picked
+        );
+        if fit.required > 0 {
+            notice = format!("{notice}, {} of them as requirements", fit.required);
+        }
+        if !fit.unknown.is_empty() {
+            notice = format!(
+                "{notice} | Left out {} as their size isn't known",
+                fit.unknown.join(", ")
+            );
         }
+        if !fit.unmet.is_empty() {
+            notice = format!(
+                "{notice} | Left out {} as something they require can't be picked",
+                fit.unmet.join(", ")
+            );
+        }
+        if self.is_loading() {
+            notice =
+                format!("{notice} | Some plugins are still loading, fit again once they finish");
+        }
+        self.notice = Some(notice);
     }
 
-    #[allow(clippy::unused_self)]
-    pub const fn tick(&self) {}
+    /// Answers the approval request being shown. Approved plugins are remembered in the trust
+    /// store, so they are only asked about again if they change
+    pub fn answer_approval(&mut self, approve: bool) {
+        let Some(request) = self.approvals.pop_front() else {
+            return;
+        };
+        if approve {
+            let saved = TrustStore::approve_and_save(&request.name, &request.path, request.sha256);
+            if let Err(err) = saved {
+                self.report.diagnostics.push(Diagnostic {
+                    plugin: request.name.clone(),
+                    line: 0,
+                    message: format!("approved for now, but failed to save the approval: {err}"),
+                    rejected: false,
+                });
+            }
+        }
+        // The plugin's thread may have given up waiting, nothing to do then
+        let _ = request.reply.send(approve);
+    }
 
-    pub fn quit(&mut self) {
+    pub const fn quit(&mut self) {
         self.should_quit = true;
     }
 }
+
+/// Shows the `hidden` Documents that the discarded plugin called `name` was hiding, as long as
+/// no other plugin still provides them according to `loaded`. Any hidden Documents from `name`
+/// itself are dropped
+fn restore_hidden(
+    category: &mut Category,
+    loaded: &mut HashMap<String, (String, String)>,
+    hidden: &mut Vec<LibraryItem>,
+    name: &str,
+) {
+    let mut items = std::mem::take(hidden);
+    // A copy from another plugin is shown in place of the built-in one, as it would have been if
+    // the discarded plugin had never loaded
+    items.sort_by_key(is_builtin);
+    for item in items {
+        let Some(item) = item.retain_documents(&mut |doc| doc.source() != name) else {
+            continue;
+        };
+        let (still_hidden, shown) = item.split_documents(&mut |doc| {
+            if loaded.contains_key(doc.id()) {
+                return false;
+            }
+            let found = (doc.source().to_string(), doc.name().to_string());
+            loaded.insert(doc.id().to_string(), found);
+            true
+        });
+        hidden.extend(still_hidden);
+        if let Some(shown) = shown {
+            hidden.extend(category.take_replaced(&shown));
+            category.add(shown);
+        }
+    }
+}
+
+/// Returns if `item` came from the catalog compiled into the binary
+fn is_builtin(item: &LibraryItem) -> bool {
+    let mut builtin = false;
+    item.for_each_document(&mut |doc| builtin |= doc.source() == BUILTIN);
+    builtin
+}
+
+/// Splits off the `Documents` in `item` that another plugin has already provided, according to
+/// `loaded`, adding a diagnostic for each that was collapsed. Built-in Documents don't count, as
+/// plugins replace those. Returns what is left of `item` and the collapsed Documents
+fn collapse_duplicates(
+    loaded: &mut HashMap<String, (String, String)>,
+    diagnostics: &mut Vec<Diagnostic>,
+    item: LibraryItem,
+) -> (Option<LibraryItem>, Option<LibraryItem>) {
+    let (item, duplicates) = item.split_documents(&mut |doc| match loaded.get(doc.id()) {
+        Some((source, name)) if source != doc.source() && source != BUILTIN => {
+            diagnostics.push(Diagnostic {
+                plugin: doc.source().to_string(),
+                line: 0,
+                message: format!(
+                    "'{}' is the same item as '{name}' from {source}, only that one is shown",
+                    doc.name()
+                ),
+                rejected: false,
+            });
+            true
+        }
+        _ => false,
+    });
+    if let Some(item) = &item {
+        item.for_each_document(&mut |doc| {
+            let found = (doc.source().to_string(), doc.name().to_string());
+            loaded.insert(doc.id().to_string(), found);
+        });
+    }
+    (item, duplicates)
+}
+
+#[cfg(test)]
+mod tests {
+    use apocalypse_library_types as sdk;
+
+    use super::*;
+
+    fn doc(id: &str, name: &str, enabled: bool, requires: &[&str]) -> sdk::Document {
+        let mut builder = sdk::Document::builder(name, format!("https://example.com/{id}"))
+            .id(id)
+            .size(1000)
+            .enabled(enabled);
+        for id in requires {
+            builder = builder.require(*id);
+        }
+        builder.build()
+    }
+
+    fn app(root: sdk::Category) -> App {
+        let (_sender, receiver) = mpsc::channel();
+        App::new(
+            root.into(),
+            receiver,
+            String::new(),
+            download::Options::default(),
+            NewItems::Enabled,
+        )
+    }
+
+    fn requirement(id: &str, required_by: &str) -> Requirement {
+        Requirement {
+            id: id.into(),
+            required_by: required_by.into(),
+        }
+    }
+
+    fn library() -> App {
+        app(sdk::Category::builder("Root")
+            .item(
+                sdk::Category::builder("Readers")
+                    .single_selection(true)
+                    .item(doc("old", "Old Reader", true, &[]))
+                    .item(doc("new", "New Reader", false, &[]))
+                    .build(),
+            )
+            .item(doc("book", "Book", true, &["new"]))
+            .build())
+    }
+
+    #[test]
+    fn requirement_is_enabled_in_place_of_its_siblings() {
+        let mut app = library();
+        app.enable_requirements(vec![requirement("new", "Book")]);
+        let new = app.category.find_document("new").unwrap();
+        assert!(new.enabled);
+        assert_eq!(new.required_by(), Some("Book"));
+        assert!(!app.category.find_document("old").unwrap().enabled);
+        let notice = app.notice.unwrap();
+        assert!(notice.contains("Also enabled New Reader (required by Book)"));
+        assert!(notice.contains("Disabled Old Reader"));
+    }
+
+    #[test]
+    fn requirement_is_unmarked_when_the_dependent_is_disabled() {
+        let mut app = library();
+        app.enable_requirements(vec![requirement("new", "Book")]);
+        app.category.for_each_document_mut(&mut |doc| {
+            if doc.id() == "book" {
+                doc.enabled = false;
+            }
+        });
+        app.category.refresh_required_by();
+        let new = app.category.find_document("new").unwrap();
+        assert!(new.enabled);
+        assert_eq!(new.required_by(), None);
+    }
+
+    #[test]
+    fn requirement_cycle_is_enabled_once() {
+        let mut app = app(sdk::Category::builder("Root")
+            .item(doc("a", "A", false, &["b"]))
+            .item(doc("b", "B", false, &["a"]))
+            .build());
+        app.enable_requirements(vec![requirement("a", "Start")]);
+        assert!(app.category.find_document("a").unwrap().enabled);
+        assert!(app.category.find_document("b").unwrap().enabled);
+        assert_eq!(
+            app.notice.as_deref(),
+            Some("Also enabled A (required by Start), B (required by A)")
+        );
+    }
+}
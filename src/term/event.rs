@@ -17,6 +17,7 @@ pub enum Event {
     /// Mouse click/scroll.
     Mouse(MouseEvent),
     /// Terminal resize.
+    #[allow(dead_code)]
     Resize(u16, u16),
     // Gain Focus Event, needed for windows compatibility.
     FocusGained,
@@ -1,4 +1,4 @@
-use std::{sync::RwLock, rc::Rc};
+use std::sync::{Arc, RwLock};
 
 use ratatui::{
     prelude::*,
@@ -8,25 +8,33 @@ use ratatui::{
     },
 };
 
-use crate::types::{Category, LibraryItem};
+use crate::{
+    trust::{ApprovalRequest, Trust},
+    types::{Category, LibraryItem},
+};
 
-use super::app::App;
+use super::app::{App, ProfileMenu};
 
 #[derive(Debug, Default, Clone)]
 pub struct StatefulListCounter {
-    state: Rc<RwLock<ListState>>,
+    state: Arc<RwLock<ListState>>,
     size: usize,
 }
 
+// The list state is shared through an `Arc<RwLock>`, but mutation should still go through `&mut`
+#[allow(clippy::needless_pass_by_ref_mut)]
 impl StatefulListCounter {
     pub fn new(size: usize) -> Self {
         Self {
-            state: Rc::new(RwLock::new(ListState::default())),
+            state: Arc::new(RwLock::new(ListState::default())),
             size,
         }
     }
 
     pub fn next(&mut self) {
+        if self.size == 0 {
+            return;
+        }
         let selected = self.state.try_write().unwrap().selected();
         let i = match selected {
             Some(i) => {
@@ -42,6 +50,9 @@ impl StatefulListCounter {
     }
 
     pub fn previous(&mut self) {
+        if self.size == 0 {
+            return;
+        }
         let selected = self.state.try_write().unwrap().selected();
         let i = match selected {
             Some(i) => {
@@ -70,7 +81,20 @@ impl StatefulListCounter {
         }
     }
 
+    /// Changes the number of items in the list, moving the selection back into the list if it's
+    /// now past the end
+    pub fn set_size(&mut self, size: usize) {
+        self.size = size;
+        let selected = self.state.try_write().unwrap().selected();
+        if selected.is_some_and(|selected| selected >= size) {
+            self.state.try_write().unwrap().select(size.checked_sub(1));
+        }
+    }
+
     pub fn set_selected(&mut self, index: usize) {
+        if self.size == 0 {
+            return;
+        }
         let index = usize::min(self.size - 1, index);
         self.state.try_write().unwrap().select(Some(index));
     }
@@ -99,7 +123,7 @@ fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
     .split(popup_layout[1])[1]
 }
 
-fn list_from_library_items(name: String, items: Option<&Vec<LibraryItem>>, selected: bool) -> List {
+fn list_from_library_items(name: String, items: Option<&Vec<LibraryItem>>, selected: bool) -> List<'_> {
     let block = Block::default()
         .borders(Borders::ALL)
         .title(Title::from(name).alignment(Alignment::Left));
@@ -130,7 +154,7 @@ fn list_from_library_items(name: String, items: Option<&Vec<LibraryItem>>, selec
 fn get_list_from_category_selected(
     category: &Category,
     selected: bool,
-) -> (List, StatefulListCounter) {
+) -> (List<'_>, StatefulListCounter) {
     if category.items.is_empty() {
         return (
             list_from_library_items(category.name().to_string(), None, selected),
@@ -151,7 +175,7 @@ fn get_list_from_category_selected(
     }
 }
 
-fn get_lists_from_app(app: &mut App) -> (List, StatefulListCounter, List, StatefulListCounter) {
+fn get_lists_from_app(app: &mut App) -> (List<'_>, StatefulListCounter, List<'_>, StatefulListCounter) {
     let result = app.get_selected_category();
     match result {
         (cat, 0) => {
@@ -212,6 +236,7 @@ pub fn render(app: &mut App, f: &mut Frame) {
             Constraint::Length(1),
             Constraint::Length(2),
             Constraint::Min(0),
+            Constraint::Length(u16::from(app.notice.is_some())),
             Constraint::Length(1),
         ],
     )
@@ -259,20 +284,26 @@ pub fn render(app: &mut App, f: &mut Frame) {
 
     // Render help
     f.render_widget(
-        Paragraph::new("ESC or ctrl-C to quit | arrow keys for navigation | space to toggle item | ENTER to download | TAB to toggle everything in the current category | 'S' to change sort mode")
+        Paragraph::new("ESC or ctrl-C to quit | arrow keys for navigation | space to toggle item | ENTER to download | TAB to toggle everything in the current category | 'S' to change sort mode | 'R' to refresh rsync sizes | 'L' for the plugin load report | 'I' for item details | 'P' for profiles | 'B' to fit to a drive size")
             .bold()
-            .alignment(Alignment::Center),
+            .alignment(Alignment::Center)
+            .wrap(Wrap { trim: true }),
         vertical[1],
     );
 
     // Render the total
     let total = app.category.human_readable_size();
-    f.render_widget(
-        Paragraph::new(format!("Total Enabled Size: {total}"))
-            .bold()
-            .alignment(Alignment::Center),
-        vertical[3],
-    );
+    render_status(app, &total, f, vertical[4]);
+
+    // Render what else changed with the last toggle
+    if let Some(notice) = &app.notice {
+        f.render_widget(
+            Paragraph::new(notice.as_str())
+                .light_cyan()
+                .alignment(Alignment::Center),
+            vertical[3],
+        );
+    }
 
     if app.download {
         let area = centered_rect(60, 60, f.size());
@@ -295,4 +326,347 @@ pub fn render(app: &mut App, f: &mut Frame) {
         // Render
         f.render_widget(paragraph, area);
     }
+
+    if app.show_report {
+        render_report(app, f);
+    }
+
+    if app.show_details {
+        render_details(app, f);
+    }
+
+    if let Some(menu) = &app.profiles {
+        render_profiles(menu, f);
+    }
+
+    if let Some(budget) = &app.budget {
+        render_budget(budget, f);
+    }
+
+    if let Some(request) = app.approvals.front() {
+        render_approval(request, app.approvals.len() - 1, f);
+    }
+}
+
+/// Renders the status line with the `total` enabled size, and what is happening in the background
+fn render_status(app: &App, total: &str, f: &mut Frame, area: Rect) {
+    let mut status = format!("Total Enabled Size: {total} | {}", app.report.summary());
+    if let Some(name) = app.profile_name() {
+        status = format!("Profile: {name} | {status}");
+    }
+    if app.is_loading() {
+        const SPINNER: [char; 8] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧'];
+        let spinner = SPINNER[(app.ticks / 4) % SPINNER.len()];
+        status = format!("{status} | {spinner} Loading: {}", app.loading.join(", "));
+    }
+    if let Some(refresh) = &app.size_refresh {
+        status = format!(
+            "{status} | Refreshing rsync sizes {}/{}",
+            refresh.done, refresh.total
+        );
+        if refresh.failed > 0 {
+            status = format!("{status} ({} failed)", refresh.failed);
+        }
+    }
+    f.render_widget(
+        Paragraph::new(status).bold().alignment(Alignment::Center),
+        area,
+    );
+}
+
+/// Renders a popup asking the user to approve an executable plugin before it is run
+fn render_approval(request: &ApprovalRequest, waiting: usize, f: &mut Frame) {
+    let area = centered_rect(70, 50, f.size());
+    f.render_widget(Clear, area); // Clear the area so we can render over it
+
+    let title = if request.trust == Trust::Changed {
+        "Executable Plugin Changed"
+    } else {
+        "New Executable Plugin"
+    };
+    let mut lines = vec![
+        Line::from(request.name.clone()).bold(),
+        Line::from(request.path.display().to_string()),
+        Line::from(format!("SHA-256: {}", request.sha256)).dim(),
+        Line::default(),
+    ];
+    if request.trust == Trust::Changed {
+        lines.push(Line::from("This plugin has changed since it was approved.").yellow());
+    }
+    lines.extend([
+        Line::from("Executable plugins run with full access to your system, only approve plugins you trust."),
+        Line::default(),
+        Line::from("Y to approve and run it | N to leave it disabled").bold(),
+    ]);
+    if waiting > 0 {
+        lines.push(Line::from(format!("{waiting} more plugin(s) waiting for approval")).dim());
+    }
+
+    let paragraph = Paragraph::new(lines)
+        .alignment(Alignment::Center)
+        .wrap(Wrap { trim: false })
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(title)
+                .title_alignment(Alignment::Center)
+                .title_style(Style::default().bold())
+                .padding(Padding::new(2, 2, 1, 1)),
+        );
+
+    // Render
+    f.render_widget(paragraph, area);
+}
+
+/// Renders a popup listing the saved profiles to load or save over, or asking for the name of a
+/// new one
+fn render_profiles(menu: &ProfileMenu, f: &mut Frame) {
+    let area = centered_rect(60, 60, f.size());
+    f.render_widget(Clear, area); // Clear the area so we can render over it
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title("Profiles")
+        .title_alignment(Alignment::Center)
+        .title_style(Style::default().bold())
+        .padding(Padding::new(2, 2, 1, 1));
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    let layout = Layout::new(
+        Direction::Vertical,
+        [Constraint::Min(0), Constraint::Length(2)],
+    )
+    .split(inner);
+
+    if let Some(name) = &menu.naming {
+        let lines = vec![
+            Line::from("Save the current selection as a new profile called:"),
+            Line::default(),
+            Line::from(format!("{name}_")).bold(),
+        ];
+        f.render_widget(Paragraph::new(lines).wrap(Wrap { trim: false }), layout[0]);
+        f.render_widget(
+            Paragraph::new("ENTER to save | ESC to cancel")
+                .bold()
+                .alignment(Alignment::Center),
+            layout[1],
+        );
+        return;
+    }
+
+    if menu.names.is_empty() {
+        f.render_widget(
+            Paragraph::new("No profiles have been saved yet").dim(),
+            layout[0],
+        );
+    } else {
+        let items: Vec<ListItem> = menu
+            .names
+            .iter()
+            .map(|name| ListItem::new(name.as_str()))
+            .collect();
+        let list = List::new(items)
+            .highlight_style(Style::new().reversed())
+            .highlight_symbol(">> ");
+        f.render_stateful_widget(
+            list,
+            layout[0],
+            &mut menu.counter.state.try_write().unwrap(),
+        );
+    }
+    f.render_widget(
+        Paragraph::new("ENTER to load | 'S' to save the current selection over it | 'N' to save as a new profile | ESC to close")
+            .bold()
+            .alignment(Alignment::Center)
+            .wrap(Wrap { trim: true }),
+        layout[1],
+    );
+}
+
+/// Renders a popup asking for the capacity to fit the selection to
+fn render_budget(budget: &str, f: &mut Frame) {
+    let area = centered_rect(60, 40, f.size());
+    f.render_widget(Clear, area); // Clear the area so we can render over it
+    let lines = vec![
+        Line::from("Replace the selection with the most important items that fit on a drive of:"),
+        Line::default(),
+        Line::from(format!("{budget}_")).bold(),
+        Line::default(),
+        Line::from("Such as 256GB, 1TB or 4TB. Items can still be toggled afterwards").dim(),
+        Line::default(),
+        Line::from("ENTER to fit | ESC to cancel").bold(),
+    ];
+    let paragraph = Paragraph::new(lines)
+        .alignment(Alignment::Center)
+        .wrap(Wrap { trim: false })
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Fit to Budget")
+                .title_alignment(Alignment::Center)
+                .title_style(Style::default().bold())
+                .padding(Padding::new(2, 2, 1, 1)),
+        );
+
+    // Render
+    f.render_widget(paragraph, area);
+}
+
+/// Renders a popup with everything known about the highlighted item
+fn render_details(app: &mut App, f: &mut Frame) {
+    let area = centered_rect(70, 70, f.size());
+    f.render_widget(Clear, area); // Clear the area so we can render over it
+
+    let field = |name: &str, value: &str| {
+        Line::from(vec![
+            Span::from(format!("{name}: ")).bold(),
+            Span::from(value.to_string()),
+        ])
+    };
+    let mut lines = Vec::new();
+    match app.highlighted() {
+        None => lines.push(Line::from("Nothing is highlighted").dim()),
+        Some((LibraryItem::Category(cat), destination)) => {
+            lines.push(Line::from(cat.name().to_string()).bold());
+            lines.push(Line::default());
+            lines.push(field("Items", &cat.items.len().to_string()));
+            lines.push(field("Enabled size", &cat.human_readable_size()));
+            lines.push(field("Destination", &destination));
+        }
+        Some((LibraryItem::Document(doc), destination)) => {
+            let metadata = doc.metadata();
+            lines.push(Line::from(doc.name().to_string()).bold());
+            if let Some(description) = &metadata.description {
+                lines.push(Line::default());
+                lines.push(Line::from(description.clone()));
+            }
+            lines.push(Line::default());
+            let optional = [
+                ("Language", &metadata.language),
+                ("License", &metadata.license),
+                ("Publisher", &metadata.publisher),
+                ("Published", &metadata.published),
+                ("Homepage", &metadata.homepage),
+            ];
+            for (name, value) in optional {
+                if let Some(value) = value {
+                    lines.push(field(name, value));
+                }
+            }
+            if !metadata.tags.is_empty() {
+                lines.push(field("Tags", &metadata.tags.join(", ")));
+            }
+            lines.push(field("Size", &doc.human_readable_size()));
+            lines.push(field("Priority", &doc.priority().to_string()));
+            lines.push(field("Url", doc.url()));
+            lines.push(field(
+                "Download type",
+                &format!("{:?}", doc.download_type()),
+            ));
+            lines.push(field("Destination", &destination));
+            lines.push(field("From", doc.source()));
+            if let Some(required_by) = doc.required_by() {
+                lines.push(field("Enabled because it is required by", required_by));
+            }
+            let references = [
+                ("Requires", doc.requires()),
+                ("Recommends", doc.recommends()),
+            ];
+            for (name, ids) in references {
+                if ids.is_empty() {
+                    continue;
+                }
+                let items: Vec<String> = ids
+                    .iter()
+                    .map(|id| match app.category.find_document(id) {
+                        Some(doc) if doc.enabled => format!("{} (enabled)", doc.name()),
+                        Some(doc) => doc.name().to_string(),
+                        None => format!("'{id}' (not in the library)"),
+                    })
+                    .collect();
+                lines.push(field(name, &items.join(", ")));
+            }
+        }
+    }
+
+    render_scrolling(
+        lines,
+        "Details (ESC to close, arrow keys to scroll)",
+        &mut app.scroll,
+        area,
+        f,
+    );
+}
+
+/// Renders `lines` wrapped in a popup at `area`, scrolled down by `scroll` rows. The scroll is
+/// limited so that the end of the text stays at the bottom of the popup, and a scrollbar shows
+/// how far down it is when it doesn't all fit
+fn render_scrolling(lines: Vec<Line>, title: &str, scroll: &mut u16, area: Rect, f: &mut Frame) {
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .title_alignment(Alignment::Center)
+        .title_style(Style::default().bold())
+        .padding(Padding::new(2, 2, 1, 1));
+    let inner = block.inner(area);
+    let paragraph = Paragraph::new(lines).wrap(Wrap { trim: false });
+    let rows = paragraph.line_count(inner.width);
+    let max = u16::try_from(rows.saturating_sub(usize::from(inner.height))).unwrap_or(u16::MAX);
+    *scroll = (*scroll).min(max);
+
+    // Render
+    f.render_widget(paragraph.block(block).scroll((*scroll, 0)), area);
+    if max > 0 {
+        let mut scrollbar_state =
+            ScrollbarState::new(usize::from(max) + 1).position(usize::from(*scroll));
+        f.render_stateful_widget(
+            Scrollbar::default().orientation(VerticalRight),
+            area,
+            &mut scrollbar_state,
+        );
+    }
+}
+
+/// Renders a popup with the results of loading each plugin and any problems with their output
+fn render_report(app: &mut App, f: &mut Frame) {
+    let area = centered_rect(80, 80, f.size());
+    f.render_widget(Clear, area); // Clear the area so we can render over it
+
+    let mut lines: Vec<Line> = vec![Line::from(app.report.summary()).bold(), Line::default()];
+    lines.extend(
+        app.loading
+            .iter()
+            .map(|name| Line::from(format!("{name}: loading...")).cyan()),
+    );
+    lines.extend(app.report.plugins.iter().map(|plugin| {
+        if let Some(error) = &plugin.error {
+            return Line::from(format!("{}: failed, {error}", plugin.name)).red();
+        }
+        let mut line = format!(
+            "{}: {} item(s) loaded, {} item(s) rejected",
+            plugin.name, plugin.items, plugin.rejected
+        );
+        if let Some(age) = plugin.age() {
+            line = format!("{line} ({age})");
+        }
+        let line = Line::from(line);
+        if plugin.rejected > 0 {
+            line.yellow()
+        } else {
+            line
+        }
+    }));
+    if !app.report.diagnostics.is_empty() {
+        lines.push(Line::default());
+        lines.extend(app.report.diagnostics.iter().map(|diagnostic| {
+            let line = Line::from(diagnostic.to_string());
+            if diagnostic.rejected {
+                line.red()
+            } else {
+                line.yellow()
+            }
+        }));
+    }
+
+    let title = "Plugin Load Report (ESC to close, arrow keys to scroll)";
+    render_scrolling(lines, title, &mut app.scroll, area, f);
 }
@@ -3,15 +3,46 @@ use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 use super::app::App;
 
 pub fn update(app: &mut App, key_event: KeyEvent) {
-    if app.download {
+    if !app.approvals.is_empty() {
+        match key_event.code {
+            KeyCode::Char('y' | 'Y') => app.answer_approval(true),
+            KeyCode::Esc | KeyCode::Char('n' | 'N') => app.answer_approval(false),
+            KeyCode::Char('c' | 'C') if key_event.modifiers == KeyModifiers::CONTROL => {
+                app.quit();
+            }
+            _ => {}
+        }
+    } else if app.profiles.is_some() {
+        update_profiles(app, key_event);
+    } else if app.budget.is_some() {
+        update_budget(app, key_event);
+    } else if app.show_report {
+        match key_event.code {
+            KeyCode::Esc | KeyCode::Char('q' | 'l' | 'L') => {
+                app.show_report = false;
+            }
+            KeyCode::Char('c' | 'C') if key_event.modifiers == KeyModifiers::CONTROL => {
+                app.show_report = false;
+            }
+            code => scroll_popup(app, code),
+        }
+    } else if app.show_details {
+        match key_event.code {
+            KeyCode::Esc | KeyCode::Char('q' | 'i' | 'I') => {
+                app.show_details = false;
+            }
+            KeyCode::Char('c' | 'C') if key_event.modifiers == KeyModifiers::CONTROL => {
+                app.show_details = false;
+            }
+            code => scroll_popup(app, code),
+        }
+    } else if app.download {
         match key_event.code {
             KeyCode::Esc | KeyCode::Char('q') => {
                 app.download = false;
             }
-            KeyCode::Char('c' | 'C') => {
-                if key_event.modifiers == KeyModifiers::CONTROL {
-                    app.download = false;
-                }
+            KeyCode::Char('c' | 'C') if key_event.modifiers == KeyModifiers::CONTROL => {
+                app.download = false;
             }
             KeyCode::Enter => app.quit(),
             _ => {}
@@ -19,10 +50,8 @@ pub fn update(app: &mut App, key_event: KeyEvent) {
     } else {
         match key_event.code {
             KeyCode::Esc | KeyCode::Char('q') => app.quit(),
-            KeyCode::Char('c' | 'C') => {
-                if key_event.modifiers == KeyModifiers::CONTROL {
-                    app.quit();
-                }
+            KeyCode::Char('c' | 'C') if key_event.modifiers == KeyModifiers::CONTROL => {
+                app.quit();
             }
             KeyCode::Up => app.previous(),
             KeyCode::Down => app.next(),
@@ -34,7 +63,101 @@ pub fn update(app: &mut App, key_event: KeyEvent) {
             KeyCode::Enter => app.download = true,
             KeyCode::Tab => app.toggle_all(),
             KeyCode::Char('s' | 'S') => app.toggle_sort_style(),
+            KeyCode::Char('r' | 'R') => app.refresh_sizes(),
+            KeyCode::Char('l' | 'L') => {
+                app.show_report = true;
+                app.scroll = 0;
+            }
+            KeyCode::Char('i' | 'I') => {
+                app.show_details = true;
+                app.scroll = 0;
+            }
+            KeyCode::Char('p' | 'P') => app.open_profiles(),
+            KeyCode::Char('b' | 'B') => app.open_budget(),
+            _ => {}
+        }
+    }
+}
+
+/// Scrolls the open popup with the arrow, page and home/end keys
+const fn scroll_popup(app: &mut App, code: KeyCode) {
+    app.scroll = match code {
+        KeyCode::Up => app.scroll.saturating_sub(1),
+        KeyCode::Down => app.scroll.saturating_add(1),
+        KeyCode::PageUp => app.scroll.saturating_sub(10),
+        KeyCode::PageDown => app.scroll.saturating_add(10),
+        KeyCode::Home => 0,
+        // Rendering limits the scroll to the end of the popup
+        KeyCode::End => u16::MAX,
+        _ => return,
+    };
+}
+
+/// Handles keys while the fit to budget popup is open, typing the capacity to fit to
+fn update_budget(app: &mut App, key_event: KeyEvent) {
+    let Some(budget) = &mut app.budget else {
+        return;
+    };
+    match key_event.code {
+        KeyCode::Char('c') if key_event.modifiers == KeyModifiers::CONTROL => app.budget = None,
+        KeyCode::Char(char) if char.is_ascii_alphanumeric() || matches!(char, '.' | ' ') => {
+            budget.push(char);
+        }
+        KeyCode::Backspace => {
+            budget.pop();
+        }
+        KeyCode::Enter if !budget.trim().is_empty() => {
+            let budget = budget.clone();
+            app.fit_to_budget(&budget);
+        }
+        KeyCode::Esc => app.budget = None,
+        _ => {}
+    }
+}
+
+/// Handles keys while the profile popup is open, either picking a profile or typing the name of a
+/// new one
+fn update_profiles(app: &mut App, key_event: KeyEvent) {
+    let Some(menu) = &mut app.profiles else {
+        return;
+    };
+    if key_event.code == KeyCode::Char('c') && key_event.modifiers == KeyModifiers::CONTROL {
+        app.profiles = None;
+        return;
+    }
+    if let Some(name) = &mut menu.naming {
+        match key_event.code {
+            KeyCode::Char(char) if char.is_alphanumeric() || matches!(char, '-' | '_' | ' ') => {
+                name.push(char);
+            }
+            KeyCode::Backspace => {
+                name.pop();
+            }
+            KeyCode::Enter if !name.trim().is_empty() => {
+                let name = name.trim().to_string();
+                app.save_profile(&name);
+            }
+            KeyCode::Esc => menu.naming = None,
             _ => {}
         }
+        return;
+    }
+    let selected = menu.names.get(menu.counter.selected()).cloned();
+    match key_event.code {
+        KeyCode::Esc | KeyCode::Char('q' | 'p' | 'P') => app.profiles = None,
+        KeyCode::Up => menu.counter.previous(),
+        KeyCode::Down => menu.counter.next(),
+        KeyCode::Char('n' | 'N') => menu.naming = Some(String::new()),
+        KeyCode::Enter => {
+            if let Some(name) = selected {
+                app.load_profile(&name);
+            }
+        }
+        KeyCode::Char('s' | 'S') => {
+            if let Some(name) = selected {
+                app.save_profile(&name);
+            }
+        }
+        _ => {}
     }
 }
@@ -0,0 +1,160 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::plugins::{Kind, Plugin};
+
+/// An executable plugin the user has approved, pinned to the contents it had when approved
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Approval {
+    /// The SHA-256 of the plugin when it was approved, as hex
+    pub sha256: String,
+    /// Where the plugin was when it was approved
+    pub path: PathBuf,
+    /// When the plugin was approved, in seconds since the unix epoch
+    pub approved: i64,
+}
+
+/// If an executable plugin may be run without asking first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trust {
+    /// The plugin has been approved, and hasn't changed since
+    Approved,
+    /// The plugin has never been approved
+    New,
+    /// The plugin was approved, but it has changed since then
+    Changed,
+}
+
+/// Asks the user to approve an executable plugin that is new or has changed, before it is run
+#[derive(Debug)]
+pub struct ApprovalRequest {
+    /// The name of the plugin
+    pub name: String,
+    /// Where the plugin is
+    pub path: PathBuf,
+    /// The SHA-256 of the plugin, as hex
+    pub sha256: String,
+    /// If the plugin is new or has changed
+    pub trust: Trust,
+    /// Where the answer is sent, `true` if the plugin may run
+    pub reply: Sender<bool>,
+}
+
+/// The executable plugins the user has approved to run, read from `trust.json`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrustStore {
+    /// The approved plugins, by plugin name
+    #[serde(default)]
+    pub plugins: BTreeMap<String, Approval>,
+}
+
+/// Returns the SHA-256 of the file at `path`, as hex
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+impl TrustStore {
+    /// Returns where the trust store is kept, next to the default config file
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("apocalypse_library").join("trust.json"))
+    }
+
+    /// Loads the trust store, a missing store means nothing has been approved yet
+    pub fn load() -> Result<Self> {
+        let Some(path) = Self::path().filter(|path| path.exists()) else {
+            return Ok(Self::default());
+        };
+        let str = fs::read_to_string(&path)
+            .map_err(|err| anyhow!("Failed to read trust store {}: {err}", path.display()))?;
+        serde_json::from_str(&str)
+            .map_err(|err| anyhow!("Invalid trust store {}: {err}", path.display()))
+    }
+
+    /// Writes the trust store, replacing the previous one
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path().ok_or_else(|| anyhow!("Could not find a config directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first so that a crash can't leave a half written store behind
+        let temp = path.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(temp, path)?;
+        Ok(())
+    }
+
+    /// Returns if the plugin called `name`, with contents hashing to `sha256`, is approved
+    pub fn check(&self, name: &str, sha256: &str) -> Trust {
+        match self.plugins.get(name) {
+            Some(approval) if approval.sha256 == sha256 => Trust::Approved,
+            Some(_) => Trust::Changed,
+            None => Trust::New,
+        }
+    }
+
+    /// Approves the plugin called `name` at `path`, as long as it still hashes to `sha256`
+    pub fn approve(&mut self, name: &str, path: &Path, sha256: String) {
+        self.plugins.insert(
+            name.to_string(),
+            Approval {
+                sha256,
+                path: path.to_path_buf(),
+                approved: Utc::now().timestamp(),
+            },
+        );
+    }
+
+    /// Removes the approval for the plugin called `name`, returning if it had one
+    pub fn revoke(&mut self, name: &str) -> bool {
+        self.plugins.remove(name).is_some()
+    }
+
+    /// Approves a plugin and saves the change, reloading the store first so that approvals made
+    /// elsewhere since it was loaded aren't lost
+    pub fn approve_and_save(name: &str, path: &Path, sha256: String) -> Result<()> {
+        let mut store = Self::load()?;
+        store.approve(name, path, sha256);
+        store.save()
+    }
+}
+
+/// Prints every plugin in `found` along with if it may run, then any approved plugins that
+/// weren't found
+pub fn print_plugins(found: &[Plugin], store: &TrustStore) {
+    for plugin in found {
+        let status = match plugin.kind {
+            Kind::Executable => match hash_file(&plugin.path) {
+                Ok(sha256) => match store.check(&plugin.name, &sha256) {
+                    Trust::Approved => "executable, approved".to_string(),
+                    Trust::New => "executable, not approved".to_string(),
+                    Trust::Changed => "executable, changed since it was approved".to_string(),
+                },
+                Err(err) => format!("executable, could not be read: {err}"),
+            },
+            Kind::Wasm => "WASM module, sandboxed".to_string(),
+            Kind::Catalog(_) => "catalog".to_string(),
+            Kind::Source => "scraping source".to_string(),
+        };
+        println!("{}: {status} ({})", plugin.name, plugin.path.display());
+    }
+    for (name, approval) in &store.plugins {
+        if !found.iter().any(|plugin| plugin.name == *name) {
+            println!(
+                "{name}: approved, but not found ({})",
+                approval.path.display()
+            );
+        }
+    }
+}
@@ -1,16 +1,27 @@
-use std::cmp::Reverse;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+};
 
+use apocalypse_library_types as sdk;
 use humansize::WINDOWS;
 use ratatui::{
-    style::{Modifier, Style},
+    style::{Color, Modifier, Style},
     widgets::ListItem,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::term::{app::SortStyle, ui::StatefulListCounter};
+use crate::{
+    filter::RsyncFilters,
+    parsing::BUILTIN,
+    term::{app::SortStyle, ui::StatefulListCounter},
+};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 /// Stores either a Category or Document so that Categories may store either
+// Most items are Documents, so boxing them would only add an allocation per item
+#[allow(clippy::large_enum_variant)]
 pub enum LibraryItem {
     Document(Document),
     Category(Category),
@@ -42,6 +53,14 @@ impl LibraryItem {
         }
     }
 
+    /// Returns the stable identifier of the contained item. Only Categories may not have one
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Self::Document(doc) => Some(doc.id()),
+            Self::Category(cat) => cat.id(),
+        }
+    }
+
     /// Returns the name of the contained item
     pub fn name(&self) -> &str {
         match self {
@@ -82,6 +101,10 @@ impl LibraryItem {
                 } else {
                     doc.enabled = false;
                 }
+                if !doc.enabled {
+                    // It's no longer enabled because of what required it
+                    doc.required_by = None;
+                }
                 doc.enabled
             }
             Self::Category(cat) => {
@@ -111,6 +134,37 @@ impl LibraryItem {
         }
     }
 
+    /// Moves any `Documents` that declare a `path` into newly created Categories matching that
+    /// path, so that they get merged into the tree when added to a Category
+    pub fn nest(self) -> Self {
+        match self {
+            Self::Document(mut doc) => {
+                let Some(path) = doc.path.take() else {
+                    return Self::Document(doc);
+                };
+                let single_selection = std::mem::take(&mut doc.single_selection);
+                path.split('/')
+                    .map(str::trim)
+                    .filter(|level| !level.is_empty())
+                    .enumerate()
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .rev()
+                    .fold(Self::Document(doc), |item, (index, name)| {
+                        let single = single_selection.get(index).copied().unwrap_or_default();
+                        Self::Category(Category::new(name.to_string(), vec![item], single))
+                    })
+            }
+            Self::Category(mut cat) => {
+                // Documents inside a Category have paths relative to that Category
+                for item in std::mem::take(&mut cat.items) {
+                    cat.add(item.nest());
+                }
+                Self::Category(cat)
+            }
+        }
+    }
+
     /// Returns if the item can be downloaded
     pub fn can_download(&self) -> bool {
         match self {
@@ -120,11 +174,24 @@ impl LibraryItem {
     }
 
     /// Converts the item to a ratatui `ListItem`
-    pub fn as_list_item(&self) -> ListItem {
+    pub fn as_list_item(&self) -> ListItem<'_> {
         let name = self.name();
         let size = self.human_readable_size();
-        let item = ListItem::new(format!("{name}:  {size}"));
+        let mut text = format!("{name}:  {size}");
+        if let Self::Document(doc) = self {
+            if let Some(required_by) = &doc.required_by {
+                text = format!("{text}  (required by {required_by})");
+            }
+            if doc.new {
+                text = format!("{text}  (new)");
+            }
+        }
+        let item = ListItem::new(text);
         let mut style = Style::default();
+        if self.has_new() {
+            // Items that weren't in the loaded profile, so the user knows to look at them
+            style = style.fg(Color::Yellow);
+        }
         if !self.enabled() {
             style = style.add_modifier(Modifier::DIM);
         }
@@ -134,6 +201,86 @@ impl LibraryItem {
         item.style(style)
     }
 
+    /// Returns if this item is, or contains, a `Document` that is new since the profile was saved
+    pub fn has_new(&self) -> bool {
+        match self {
+            Self::Document(doc) => doc.new,
+            Self::Category(cat) => cat.items.iter().any(Self::has_new),
+        }
+    }
+
+    /// Sets the source of every `Document` in this item to the plugin called `name`
+    pub fn set_source(&mut self, name: &str) {
+        self.for_each_document_mut(&mut |doc| doc.source = name.to_string());
+    }
+
+    /// Calls `f` with every `Document` in this item
+    pub fn for_each_document_mut(&mut self, f: &mut impl FnMut(&mut Document)) {
+        match self {
+            Self::Document(doc) => f(doc),
+            Self::Category(cat) => cat.for_each_document_mut(f),
+        }
+    }
+
+    /// Calls `f` with every `Document` in this item, without changing them
+    pub fn for_each_document(&self, f: &mut impl FnMut(&Document)) {
+        match self {
+            Self::Document(doc) => f(doc),
+            Self::Category(cat) => cat.items.iter().for_each(|item| item.for_each_document(f)),
+        }
+    }
+
+    /// Keeps only the `Documents` in this item that `f` returns true for, returning `None` if
+    /// nothing is left
+    pub fn retain_documents(self, f: &mut impl FnMut(&Document) -> bool) -> Option<Self> {
+        match self {
+            Self::Document(doc) => f(&doc).then_some(Self::Document(doc)),
+            Self::Category(mut cat) => {
+                cat.retain_documents(f);
+                (!cat.items.is_empty()).then_some(Self::Category(cat))
+            }
+        }
+    }
+
+    /// Splits this item into the `Documents` that `f` returns false for and those it returns true
+    /// for, each in a copy of the categories they were in. Either is `None` if it is empty
+    pub fn split_documents(
+        self,
+        f: &mut impl FnMut(&Document) -> bool,
+    ) -> (Option<Self>, Option<Self>) {
+        match self {
+            Self::Document(doc) if f(&doc) => (None, Some(Self::Document(doc))),
+            Self::Document(doc) => (Some(Self::Document(doc)), None),
+            Self::Category(mut cat) => {
+                let taken = cat.take_documents(f).map(Self::Category);
+                (
+                    (!cat.items.is_empty()).then_some(Self::Category(cat)),
+                    taken,
+                )
+            }
+        }
+    }
+
+    /// Adds the requirements of every enabled `Document` in this item to `requirements`
+    pub fn collect_requirements(&self, requirements: &mut Vec<Requirement>) {
+        if !self.enabled() {
+            return;
+        }
+        match self {
+            Self::Document(doc) => {
+                requirements.extend(doc.requires.iter().map(|id| Requirement {
+                    id: id.clone(),
+                    required_by: doc.name.clone(),
+                }));
+            }
+            Self::Category(cat) => {
+                for item in &cat.items {
+                    item.collect_requirements(requirements);
+                }
+            }
+        }
+    }
+
     /// Returns if the contained item is a Document
     pub const fn is_document(&self) -> bool {
         match self {
@@ -143,20 +290,66 @@ impl LibraryItem {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Deserialize, Clone, Copy)]
-/// The download method to use for a `Document`
-pub enum DownloadType {
-    /// Download files with HTTP(s) GET requests
-    Http,
-    /// Download files by running the rsync application
-    Rsync,
-    /// Supports either HTTP GET or Rsync. Prefers Rsync by default
-    Either,
+pub use sdk::DownloadType;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// Sizes reported by a rsync dry run of a `Document`
+pub struct SizeStats {
+    /// Total size in bytes of the File(s) on the server, after filtering
+    pub total: u64,
+    /// Size in bytes of the File(s) that would still need to be transferred
+    pub remaining: u64,
+    /// When these sizes were gathered, in seconds since the unix epoch
+    pub checked: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An item that a `Document` which has just been enabled requires, see `Category::enable_required`
+pub struct Requirement {
+    /// The stable id of the required item
+    pub id: String,
+    /// The name of the `Document` that requires it
+    pub required_by: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Default)]
+/// Descriptive details about a `Document`, shown when the user looks at it more closely
+pub struct Metadata {
+    /// What the Document contains
+    pub description: Option<String>,
+    /// The language code of the content, such as `en`
+    pub language: Option<String>,
+    /// The license the content is distributed under
+    pub license: Option<String>,
+    /// Who publishes the content
+    pub publisher: Option<String>,
+    /// When this version of the content was published
+    pub published: Option<String>,
+    /// Keywords describing the content
+    pub tags: Vec<String>,
+    /// The url of a page about the content
+    pub homepage: Option<String>,
+}
+
+impl Metadata {
+    /// Metadata with nothing set
+    const EMPTY: Self = Self {
+        description: None,
+        language: None,
+        license: None,
+        publisher: None,
+        published: None,
+        tags: Vec::new(),
+        homepage: None,
+    };
+}
+
+#[derive(Debug)]
 /// Represents a File or Group of files to download
 pub struct Document {
+    /// A stable identifier that other items refer to this Document by, a hash of the url if the
+    /// plugin didn't provide one
+    id: String,
     /// The name of the Document(s)
     name: String,
     /// The path of the File(s) to get
@@ -165,6 +358,38 @@ pub struct Document {
     size: u64,
     /// The method to use to download the File(s)
     download_type: DownloadType,
+    /// The MIME type of the File, used to sanity check HTTP downloads
+    mime_type: Option<String>,
+    /// The url of a `.zsync` control file for the File, used to only download the changed parts
+    /// of a File when a previous version of it exists locally
+    zsync_url: Option<String>,
+    /// Rules limiting which of the File(s) are downloaded
+    filters: Option<RsyncFilters>,
+    /// The Categories this Document belongs in, separated by `/`, such as `Linux/Arch/Mirrors`.
+    /// Lets plugins output flat records instead of nesting every Document in Categories
+    path: Option<String>,
+    /// Which levels of `path` only allow a single selection, missing levels default to false
+    single_selection: Vec<bool>,
+    /// Accurate sizes gathered with a rsync dry run, these replace the size provided by the plugin
+    size_stats: Option<SizeStats>,
+    /// The name of the plugin this Document was loaded from
+    source: String,
+    /// Why this Document can't be downloaded, for placeholders such as plugins that aren't
+    /// allowed to run
+    disabled: Option<String>,
+    /// Descriptive details about the Document
+    metadata: Metadata,
+    /// The ids of items this Document is useless without, enabled along with it
+    requires: Vec<String>,
+    /// The ids of items that go well with this Document
+    recommends: Vec<String>,
+    /// The name of the Document that caused this one to be enabled, if it was enabled as a
+    /// requirement rather than by the user
+    required_by: Option<String>,
+    /// How important the Document is, from 1 to 10, used when fitting the selection to a budget
+    priority: u8,
+    /// If this Document wasn't in the profile that was loaded
+    new: bool,
     /// Should these File(s) be downloaded
     pub enabled: bool,
 }
@@ -175,14 +400,62 @@ impl Document {
     pub fn new(name: String, url: String, size: u64, d_type: DownloadType) -> Self {
         let enabled = d_type != DownloadType::Rsync || (!crate::IS_WINDOWS && *crate::HAS_RSYNC);
         Self {
+            id: url_id(&url),
             name,
             url,
             size,
             download_type: d_type,
+            mime_type: None,
+            zsync_url: None,
+            filters: None,
+            path: None,
+            single_selection: Vec::new(),
+            size_stats: None,
+            source: String::new(),
+            disabled: None,
+            metadata: Metadata::EMPTY,
+            requires: Vec::new(),
+            recommends: Vec::new(),
+            required_by: None,
+            priority: sdk::DEFAULT_PRIORITY,
+            new: false,
             enabled,
         }
     }
 
+    /// Creates a placeholder that can't be downloaded, shown as `name` and the `reason` why,
+    /// in the Categories of `path`
+    pub fn disabled(name: String, reason: String, path: String) -> Self {
+        Self {
+            // Placeholders have no url, and shouldn't be collapsed into one another
+            id: format!("{path}/{name}"),
+            name,
+            url: String::new(),
+            size: 0,
+            download_type: DownloadType::Http,
+            mime_type: None,
+            zsync_url: None,
+            filters: None,
+            path: Some(path),
+            single_selection: Vec::new(),
+            size_stats: None,
+            source: String::new(),
+            disabled: Some(reason),
+            metadata: Metadata::EMPTY,
+            requires: Vec::new(),
+            recommends: Vec::new(),
+            required_by: None,
+            priority: sdk::DEFAULT_PRIORITY,
+            new: false,
+            enabled: false,
+        }
+    }
+
+    /// Returns the stable identifier of this Document
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
     /// Returns a reference to the name of this Document
     pub fn name(&self) -> &str {
         &self.name
@@ -193,9 +466,23 @@ impl Document {
         &self.url
     }
 
-    /// Returns the size in bytes
-    pub const fn size(&self) -> u64 {
-        self.size
+    /// Returns the size in bytes. This is the size from the last rsync dry run if there has been
+    /// one, otherwise the size the plugin gave for the files that pass its filters if it did
+    pub fn size(&self) -> u64 {
+        self.size_stats.map_or_else(
+            || {
+                self.filters
+                    .as_ref()
+                    .and_then(RsyncFilters::size)
+                    .unwrap_or(self.size)
+            },
+            |stats| stats.total,
+        )
+    }
+
+    /// Replaces the size of this Document with the results of a rsync dry run
+    pub const fn set_size_stats(&mut self, stats: SizeStats) {
+        self.size_stats = Some(stats);
     }
 
     /// Returns the download type of this Document
@@ -203,10 +490,67 @@ impl Document {
         self.download_type
     }
 
+    /// Returns the declared MIME type of this Document, if it has one
+    pub fn mime_type(&self) -> Option<&str> {
+        self.mime_type.as_deref()
+    }
+
+    /// Returns the url of the zsync control file for this Document, if it has one
+    pub fn zsync_url(&self) -> Option<&str> {
+        self.zsync_url.as_deref()
+    }
+
+    /// Returns the filters limiting which File(s) of this Document are downloaded, if it has any
+    pub const fn filters(&self) -> Option<&RsyncFilters> {
+        self.filters.as_ref()
+    }
+
+    /// Returns the descriptive details of this Document
+    pub const fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Returns the name of the plugin this Document was loaded from
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns the ids of the items this Document requires
+    pub fn requires(&self) -> &[String] {
+        &self.requires
+    }
+
+    /// Returns the ids of the items this Document recommends
+    pub fn recommends(&self) -> &[String] {
+        &self.recommends
+    }
+
+    /// Sets if this Document is new since the loaded profile was saved
+    pub const fn set_new(&mut self, new: bool) {
+        self.new = new;
+    }
+
+    /// Returns the name of the Document that caused this one to be enabled, if it was enabled as
+    /// a requirement
+    pub fn required_by(&self) -> Option<&str> {
+        self.required_by.as_deref()
+    }
+
+    /// Sets the name of the Document that caused this one to be enabled, or `None` if the user
+    /// chose it
+    pub fn set_required_by(&mut self, required_by: Option<String>) {
+        self.required_by = required_by;
+    }
+
+    /// Returns how important this Document is, from 1 to 10
+    pub const fn priority(&self) -> u8 {
+        self.priority
+    }
+
     /// Returns the size of this document, or zero if it's not enabled
-    pub const fn enabled_size(&self) -> u64 {
+    pub fn enabled_size(&self) -> u64 {
         if self.enabled {
-            self.size
+            self.size()
         } else {
             0
         }
@@ -215,19 +559,35 @@ impl Document {
     /// Returns if we can download this Document
     /// In cases such as a rsync Document on a windows system we cant download it
     pub fn can_download(&self) -> bool {
-        self.download_type != DownloadType::Rsync || (!crate::IS_WINDOWS && *crate::HAS_RSYNC)
+        self.disabled.is_none()
+            && (self.download_type != DownloadType::Rsync
+                || (!crate::IS_WINDOWS && *crate::HAS_RSYNC))
     }
 
-    /// Returns the size of the item formatted to be human readable
+    /// Returns the size of the item formatted to be human readable, or why it can't be
+    /// downloaded for placeholders
     pub fn human_readable_size(&self) -> String {
-        humansize::format_size(self.size, WINDOWS)
+        if let Some(reason) = &self.disabled {
+            return reason.clone();
+        }
+        let mut size = humansize::format_size(self.size(), WINDOWS);
+        if let Some(stats) = self.size_stats {
+            let remaining = humansize::format_size(stats.remaining, WINDOWS);
+            size = format!("{size} ({remaining} to sync)");
+        }
+        if self.filters.is_some() {
+            size = format!("{size} (filtered)");
+        }
+        size
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 /// Contains a navigable list of items grouped together. Items may be either Documents or
 /// Categories of their own
 pub struct Category {
+    /// A stable identifier that the Category is merged by, if the plugin provided one
+    id: Option<String>,
     /// The name of the category
     name: String,
     /// The items contained in the category, may be a mix of Documents and Categories
@@ -236,7 +596,6 @@ pub struct Category {
     single_selection: bool,
     /// Is this category enabled for download
     pub enabled: bool,
-    #[serde(skip)]
     /// The list cursor pointer
     pub counter: StatefulListCounter,
 }
@@ -253,6 +612,7 @@ impl Category {
         let enabled = items.iter().any(LibraryItem::can_download);
         let len = items.len();
         Self {
+            id: None,
             name,
             items,
             enabled,
@@ -261,18 +621,106 @@ impl Category {
         }
     }
 
-    /// Fixes the `StatefulListCounter` which is broken when this object is deserialized
-    pub fn fix_counter(&mut self) {
-        self.counter = StatefulListCounter::new(self.items.len());
-        self.counter.selected();
+    /// Updates the size of the `StatefulListCounter` of this `Category` and all contained categories
+    /// to match their contents, keeping the current selection where possible
+    pub fn update_counters(&mut self) {
+        self.counter.set_size(self.items.len());
         for item in &mut self.items {
             match item {
                 LibraryItem::Document(_) => {}
-                LibraryItem::Category(cat) => cat.fix_counter(),
+                LibraryItem::Category(cat) => cat.update_counters(),
             }
         }
     }
 
+    /// Calls `f` with every `Document` contained in this `Category` and its sub categories
+    pub fn for_each_document_mut(&mut self, f: &mut impl FnMut(&mut Document)) {
+        for item in &mut self.items {
+            item.for_each_document_mut(f);
+        }
+    }
+
+    /// Removes every `Document` that was loaded from the plugin called `name`, along with any
+    /// categories left empty by doing so
+    pub fn remove_source(&mut self, name: &str) {
+        self.retain_documents(&mut |doc| doc.source != name);
+    }
+
+    /// Keeps only the `Documents` that `f` returns true for, along with the categories that still
+    /// contain any
+    pub fn retain_documents(&mut self, f: &mut impl FnMut(&Document) -> bool) {
+        self.items.retain_mut(|item| match item {
+            LibraryItem::Document(doc) => f(doc),
+            LibraryItem::Category(cat) => {
+                cat.retain_documents(f);
+                !cat.items.is_empty()
+            }
+        });
+    }
+
+    /// Removes the `Documents` that `f` returns true for, along with any categories left empty by
+    /// doing so. Returns them in a copy of this Category, so that they can be added back later
+    pub fn take_documents(&mut self, f: &mut impl FnMut(&Document) -> bool) -> Option<Self> {
+        let mut taken = Vec::new();
+        let mut kept = Vec::with_capacity(self.items.len());
+        for item in std::mem::take(&mut self.items) {
+            match item {
+                LibraryItem::Document(doc) if f(&doc) => taken.push(LibraryItem::Document(doc)),
+                LibraryItem::Document(_) => kept.push(item),
+                LibraryItem::Category(mut cat) => {
+                    if let Some(sub) = cat.take_documents(f) {
+                        taken.push(LibraryItem::Category(sub));
+                    }
+                    if !cat.items.is_empty() {
+                        kept.push(LibraryItem::Category(cat));
+                    }
+                }
+            }
+        }
+        self.items = kept;
+        (!taken.is_empty()).then(|| Self {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            single_selection: self.single_selection,
+            enabled: self.enabled,
+            counter: StatefulListCounter::new(taken.len()),
+            items: taken,
+        })
+    }
+
+    /// Removes the built-in Documents that `item` replaces, those with the same id anywhere in
+    /// the library, so that plugins can override the catalog compiled into the binary. Returns
+    /// the removed Documents nested as they were, so that they can be added back if the plugin
+    /// is discarded
+    pub fn take_replaced(&mut self, item: &LibraryItem) -> Vec<LibraryItem> {
+        let mut ids = HashSet::new();
+        item.for_each_document(&mut |doc| {
+            if doc.source != BUILTIN {
+                ids.insert(doc.id.clone());
+            }
+        });
+        if ids.is_empty() {
+            return Vec::new();
+        }
+        self.take_documents(&mut |doc| doc.source == BUILTIN && ids.contains(&doc.id))
+            .map(|cat| cat.items)
+            .unwrap_or_default()
+    }
+
+    /// Returns the stable identifier of the Category, if it has one
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Returns if `other` is the same Category as this one, so that they should be merged. This
+    /// compares ids when both have one, and otherwise names
+    pub fn is_same(&self, other: &Self) -> bool {
+        match (&self.id, &other.id) {
+            (Some(id), Some(other)) => id == other,
+            _ => self.name.eq_ignore_ascii_case(&other.name),
+        }
+    }
+
     /// Returns a reference to the Category's name
     pub fn name(&self) -> &str {
         &self.name
@@ -326,12 +774,31 @@ impl Category {
         }
     }
 
+    /// Follows the selection down `depth` Categories to find the highlighted item, returning it
+    /// along with the names of the Categories it is in below this one
+    pub fn highlighted(&self, depth: usize) -> Option<(Vec<&str>, &LibraryItem)> {
+        let mut path = Vec::new();
+        let mut cat = self;
+        for _ in 0..depth {
+            match cat.items.get(cat.counter.clone().selected()) {
+                Some(LibraryItem::Category(inner)) if !inner.items.is_empty() => {
+                    path.push(inner.name());
+                    cat = inner;
+                }
+                _ => break,
+            }
+        }
+        cat.items
+            .get(cat.counter.clone().selected())
+            .map(|item| (path, item))
+    }
+
     /// Returns if the item currently selected in this `Category` is a `Category`
     pub fn is_selected_category(&self) -> bool {
         let index = self.counter.clone().selected();
-        match &self.items[index] {
-            LibraryItem::Document(_) => false,
-            LibraryItem::Category(_) => true,
+        match self.items.get(index) {
+            None | Some(LibraryItem::Document(_)) => false,
+            Some(LibraryItem::Category(_)) => true,
         }
     }
 
@@ -339,24 +806,32 @@ impl Category {
     /// `Documents`
     pub fn is_selected_last(&self) -> bool {
         let index = self.counter.clone().selected();
-        match &self.items[index] {
-            LibraryItem::Document(_) => false,
-            LibraryItem::Category(cat) => cat.items.iter().all(LibraryItem::is_document),
+        match self.items.get(index) {
+            None | Some(LibraryItem::Document(_)) => false,
+            Some(LibraryItem::Category(cat)) => cat.items.iter().all(LibraryItem::is_document),
         }
     }
 
-    /// If this `Category` is not `single_selection` toggle the state of all items in this `Category`
-    pub fn toggle_all_items(&mut self) {
+    /// If this `Category` is not `single_selection` toggle the state of all items in this `Category`.
+    /// Returns the requirements of the items that were enabled
+    pub fn toggle_all_items(&mut self) -> Vec<Requirement> {
+        let before = self.enabled_states();
         if !self.single_selection() {
             self.items.iter_mut().for_each(|item| {
                 let state = !item.enabled();
                 item.set_enabled(state);
             });
         }
+        self.requirements_since(&before)
     }
 
-    /// Toggles the currently selected item in the `Category`
-    pub fn toggle_selected_item(&mut self) {
+    /// Toggles the currently selected item in the `Category`. Returns the requirements of the
+    /// items that were enabled, which the caller should enable with `enable_required`
+    pub fn toggle_selected_item(&mut self) -> Vec<Requirement> {
+        if self.items.is_empty() {
+            return Vec::new();
+        }
+        let before = self.enabled_states();
         let single_selection = self.single_selection();
         let index = self.counter.selected();
         let item = &self.items[index];
@@ -385,6 +860,95 @@ impl Category {
             // Not single selection, just toggle the item
             self.items[index].set_enabled(!enabled);
         }
+        if let LibraryItem::Document(doc) = &mut self.items[index] {
+            // The user has chosen this one themselves now
+            doc.required_by = None;
+        }
+        self.requirements_since(&before)
+    }
+
+    /// Returns if each item is enabled, to compare against after changing them
+    fn enabled_states(&self) -> Vec<bool> {
+        self.items.iter().map(LibraryItem::enabled).collect()
+    }
+
+    /// Returns the requirements of every item that is enabled now, but wasn't `before`
+    fn requirements_since(&self, before: &[bool]) -> Vec<Requirement> {
+        let mut requirements = Vec::new();
+        for (item, was_enabled) in self.items.iter().zip(before) {
+            if !was_enabled {
+                item.collect_requirements(&mut requirements);
+            }
+        }
+        requirements
+    }
+
+    /// Returns the `Document` with the stable `id` in this `Category` or its sub categories
+    pub fn find_document(&self, id: &str) -> Option<&Document> {
+        self.items.iter().find_map(|item| match item {
+            LibraryItem::Document(doc) => (doc.id() == id).then_some(doc),
+            LibraryItem::Category(cat) => cat.find_document(id),
+        })
+    }
+
+    /// Enables the `Document` with the stable `id` along with the Categories it is in, noting that
+    /// it was enabled because `required_by` needs it. Other items in single selection Categories
+    /// along the way are disabled, and their names added to `disabled`. Returns `None` if there is
+    /// no such `Document` that can be downloaded, otherwise if anything had to be enabled
+    pub fn enable_required(
+        &mut self,
+        id: &str,
+        required_by: &str,
+        disabled: &mut Vec<String>,
+    ) -> Option<bool> {
+        let mut changed = false;
+        let index = self.items.iter_mut().position(|item| match item {
+            LibraryItem::Document(doc) => doc.id() == id && doc.can_download(),
+            LibraryItem::Category(cat) => cat
+                .enable_required(id, required_by, disabled)
+                .map(|inner| changed = inner)
+                .is_some(),
+        })?;
+        if let LibraryItem::Document(doc) = &mut self.items[index] {
+            if !doc.enabled {
+                doc.enabled = true;
+                doc.required_by = Some(required_by.to_string());
+                changed = true;
+            }
+        }
+        if self.single_selection {
+            for (i, item) in self.items.iter_mut().enumerate() {
+                if i != index && item.enabled() {
+                    item.set_enabled(false);
+                    disabled.push(item.name().to_string());
+                    changed = true;
+                }
+            }
+        }
+        if let LibraryItem::Category(cat) = &mut self.items[index] {
+            changed |= !cat.enabled;
+            cat.enabled = true;
+        }
+        Some(changed)
+    }
+
+    /// Updates which `Document` each Document that was enabled as a requirement is required by,
+    /// now that items may have been toggled. Documents that are disabled, or that nothing enabled
+    /// requires any more, are no longer marked as required
+    pub fn refresh_required_by(&mut self) {
+        let mut requirements = Vec::new();
+        for item in &self.items {
+            item.collect_requirements(&mut requirements);
+        }
+        let mut by_id = HashMap::new();
+        for Requirement { id, required_by } in requirements {
+            by_id.entry(id).or_insert(required_by);
+        }
+        self.for_each_document_mut(&mut |doc| {
+            if doc.required_by.is_some() {
+                doc.required_by = by_id.get(&doc.id).filter(|_| doc.enabled).cloned();
+            }
+        });
     }
 
     /// Sorts the `Category` contents by the provided style
@@ -392,10 +956,14 @@ impl Category {
     ///  # Arguments
     ///
     ///  * `style` - the sort order to use
-    ///  Currently either Alphabetically A-Z or by size decending
+    ///    Currently either Alphabetically A-Z or by size decending
     ///
     pub fn sort(&mut self, style: SortStyle) {
-        let name = self.items[self.counter.selected()].name().to_owned();
+        if self.items.is_empty() {
+            return;
+        }
+        let selected = &self.items[self.counter.selected()];
+        let (id, name) = (selected.id().map(str::to_owned), selected.name().to_owned());
         let old_selected = self.counter.selected();
         match style {
             SortStyle::Alphabetical => {
@@ -413,7 +981,13 @@ impl Category {
             }
         }
         if old_selected != 0 {
-            let found = self.items.iter().enumerate().find(|enumerated| enumerated.1.name().eq_ignore_ascii_case(&name));
+            // Names aren't unique, so find the selected item again by id where it has one
+            let found = self.items.iter().enumerate().find(|(_, item)| {
+                id.as_deref().map_or_else(
+                    || item.name().eq_ignore_ascii_case(&name),
+                    |id| item.id() == Some(id),
+                )
+            });
             if let Some((i, _)) = found {
                 self.counter.set_selected(i);
             }
@@ -421,11 +995,25 @@ impl Category {
     }
 
     /// Adds the provided `LibraryItem` to this `Category`
-    /// If the provided item is a `Category` it will check to see if it has the same name as an
-    /// existing `Category` and merge them together
+    /// If the provided item is a `Category` it will check to see if it is the same as an existing
+    /// `Category`, see `Category::is_same`, and merge them together
     pub fn add(&mut self, mut item: LibraryItem) {
-        if self.single_selection && !self.items.is_empty() {
-            item.set_enabled(false);
+        if self.single_selection && self.items.iter().any(LibraryItem::enabled) {
+            // An item chosen in the profile takes over from items only enabled for being new
+            let replaces_new = item.enabled()
+                && !item.has_new()
+                && self
+                    .items
+                    .iter()
+                    .filter(|item| item.enabled())
+                    .all(LibraryItem::has_new);
+            if replaces_new {
+                self.items.iter_mut().for_each(|item| {
+                    item.set_enabled(false);
+                });
+            } else {
+                item.set_enabled(false);
+            }
         }
         match item {
             LibraryItem::Document(_) => self.items.push(item),
@@ -435,13 +1023,7 @@ impl Category {
                 }
                 if let Some(merge) = self.items.iter_mut().find_map(|e| match e {
                     LibraryItem::Document(_) => None,
-                    LibraryItem::Category(cat) => {
-                        if cat.name.eq_ignore_ascii_case(category.name()) {
-                            Some(cat)
-                        } else {
-                            None
-                        }
-                    }
+                    LibraryItem::Category(cat) => cat.is_same(&category).then_some(cat),
                 }) {
                     // End of condition, merge the two categories if their names match
                     for item in category.items {
@@ -454,3 +1036,68 @@ impl Category {
         }
     }
 }
+
+/// Returns the id of a `Document` that doesn't have one of its own, from a hash of its `url`
+fn url_id(url: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(url.as_bytes()));
+    format!("url-{}", &hash[..16])
+}
+
+impl From<sdk::LibraryItem> for LibraryItem {
+    fn from(item: sdk::LibraryItem) -> Self {
+        match item {
+            sdk::LibraryItem::Document(doc) => Self::Document(doc.into()),
+            sdk::LibraryItem::Category(cat) => Self::Category(cat.into()),
+        }
+    }
+}
+
+impl From<sdk::Document> for Document {
+    fn from(doc: sdk::Document) -> Self {
+        Self {
+            id: doc.id.unwrap_or_else(|| url_id(&doc.url)),
+            name: doc.name,
+            url: doc.url,
+            size: doc.size,
+            download_type: doc.download_type,
+            mime_type: doc.mime_type,
+            zsync_url: doc.zsync_url,
+            filters: doc.filters.map(Into::into),
+            path: doc.path,
+            single_selection: doc.single_selection,
+            size_stats: None,
+            source: String::new(),
+            disabled: None,
+            metadata: Metadata {
+                description: doc.description,
+                language: doc.language,
+                license: doc.license,
+                publisher: doc.publisher,
+                published: doc.published,
+                tags: doc.tags,
+                homepage: doc.homepage,
+            },
+            requires: doc.requires,
+            recommends: doc.recommends,
+            required_by: None,
+            priority: doc.priority.unwrap_or(sdk::DEFAULT_PRIORITY).clamp(1, 10),
+            new: false,
+            enabled: doc.enabled,
+        }
+    }
+}
+
+impl From<sdk::Category> for Category {
+    fn from(cat: sdk::Category) -> Self {
+        let items: Vec<LibraryItem> = cat.items.into_iter().map(Into::into).collect();
+        let len = items.len();
+        Self {
+            id: cat.id,
+            name: cat.name,
+            items,
+            single_selection: cat.single_selection,
+            enabled: cat.enabled,
+            counter: StatefulListCounter::new(len),
+        }
+    }
+}
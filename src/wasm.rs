@@ -0,0 +1,377 @@
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use reqwest::{blocking::Client, redirect, Url};
+use wasmtime::{
+    Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    Trap,
+};
+use wasmtime_wasi::{
+    p1::{self, WasiP1Ctx},
+    p2::pipe::{MemoryInputPipe, MemoryOutputPipe},
+    FsPerms, I32Exit, WasiCtxBuilder,
+};
+
+use crate::config::{Grants, PluginInput};
+
+/// The name of the module that the host API is imported from
+const HOST_MODULE: &str = "apocalypse_library";
+/// Where the plugin's cache folder appears inside the plugin, if it is granted one
+const GUEST_CACHE_DIR: &str = "/cache";
+/// The most a plugin may write to stdout
+const MAX_STDOUT: usize = 256 * 1024 * 1024;
+/// The most a plugin may write to stderr, anything more is dropped
+const MAX_STDERR: usize = 1024 * 1024;
+/// The most redirects followed for a single HTTP request
+const MAX_REDIRECTS: usize = 10;
+
+/// Returned by the host API when the plugin hasn't been granted access to a url
+pub const ERR_DENIED: i32 = -1;
+/// Returned by the host API when a request fails
+pub const ERR_FAILED: i32 = -2;
+/// Returned by the host API when it is passed an invalid pointer, length or handle
+pub const ERR_INVALID: i32 = -3;
+/// Returned by the host API when a response is larger than the plugin may fetch
+pub const ERR_TOO_LARGE: i32 = -4;
+
+/// A response fetched for a plugin
+struct Response {
+    /// The HTTP status code
+    status: u16,
+    /// The body of the response
+    body: Vec<u8>,
+}
+
+/// The state of the host HTTP API for a single plugin
+struct Http {
+    /// The client requests are made with, which only follows redirects to allowed urls
+    client: Option<Client>,
+    /// The urls the plugin may fetch, along with everything below them
+    allowed: Vec<Url>,
+    /// The largest response the plugin may fetch, in bytes
+    max_response: u64,
+    /// Responses by handle, closed responses are `None`
+    responses: Vec<Option<Response>>,
+}
+
+impl Http {
+    fn new(grants: &Grants, timeout: Duration) -> Self {
+        // Invalid urls are rejected when the config is loaded
+        let allowed: Vec<Url> = grants
+            .http
+            .iter()
+            .filter_map(|url| Url::parse(url).ok())
+            .collect();
+        let policy_allowed = allowed.clone();
+        let policy = redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if is_allowed(&policy_allowed, attempt.url()) {
+                attempt.follow()
+            } else {
+                attempt.error("redirected to a url the plugin has not been granted")
+            }
+        });
+        // Without any grants there's nothing the client could be used for
+        let client = if allowed.is_empty() {
+            None
+        } else {
+            Client::builder()
+                .timeout(timeout)
+                .redirect(policy)
+                .build()
+                .ok()
+        };
+        Self {
+            client,
+            allowed,
+            max_response: grants.max_response_bytes().unwrap_or(u64::MAX),
+            responses: Vec::new(),
+        }
+    }
+
+    /// Fetches `url`, returning a handle to the response or an error code
+    fn get(&mut self, url: &str) -> i32 {
+        let Ok(url) = Url::parse(url) else {
+            return ERR_INVALID;
+        };
+        if !is_allowed(&self.allowed, &url) {
+            return ERR_DENIED;
+        }
+        let Some(client) = &self.client else {
+            return ERR_FAILED;
+        };
+        let Ok(response) = client.get(url).send() else {
+            return ERR_FAILED;
+        };
+        let status = response.status().as_u16();
+        let mut body = Vec::new();
+        // Read one byte past the limit, so we can tell if the response was too large
+        if response
+            .take(self.max_response + 1)
+            .read_to_end(&mut body)
+            .is_err()
+        {
+            return ERR_FAILED;
+        }
+        if body.len() as u64 > self.max_response {
+            return ERR_TOO_LARGE;
+        }
+        self.responses.push(Some(Response { status, body }));
+        i32::try_from(self.responses.len() - 1).unwrap_or(ERR_FAILED)
+    }
+
+    /// Returns the open response with the provided `handle`
+    fn response(&self, handle: i32) -> Option<&Response> {
+        let index = usize::try_from(handle).ok()?;
+        self.responses.get(index)?.as_ref()
+    }
+}
+
+/// Returns if `url` is below any of the `allowed` urls: it has the same scheme, host and port, and
+/// its path starts with the same segments. Comparing the parsed urls means that a grant for
+/// `https://example.com/files` doesn't allow `https://example.com.evil.net/` or
+/// `https://example.com/files-private/`
+fn is_allowed(allowed: &[Url], url: &Url) -> bool {
+    let segments = path_segments(url);
+    allowed.iter().any(|grant| {
+        let grant_segments = path_segments(grant);
+        grant.scheme() == url.scheme()
+            && grant.host() == url.host()
+            && grant.port_or_known_default() == url.port_or_known_default()
+            && segments.starts_with(&grant_segments)
+    })
+}
+
+/// Returns the segments of the path of `url`, leaving out empty ones so that a trailing `/` doesn't
+/// matter
+fn path_segments(url: &Url) -> Vec<&str> {
+    url.path_segments()
+        .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+/// Everything the plugin has access to while it runs
+struct State {
+    /// The WASI context, which provides stdin, stdout, env vars and any granted folders
+    wasi: WasiP1Ctx,
+    /// Limits on the memory the plugin may use
+    limits: StoreLimits,
+    /// The host HTTP API
+    http: Http,
+}
+
+/// Returns the memory exported by the plugin
+fn memory(caller: &mut Caller<'_, State>) -> Option<Memory> {
+    caller.get_export("memory").and_then(Extern::into_memory)
+}
+
+/// Adds the host API to `linker`. Each function returns a negative error code on failure:
+///
+/// * `http_get(url_ptr, url_len) -> handle` fetches a url, which must be granted
+/// * `http_status(handle) -> status` returns the HTTP status code of a response
+/// * `http_body_len(handle) -> len` returns the length of the body of a response
+/// * `http_read(handle, offset, buf_ptr, buf_len) -> read` copies part of the body of a response
+///   into the plugin's memory, returning the number of bytes copied
+/// * `http_close(handle)` frees a response
+fn add_host_api(linker: &mut Linker<State>) -> wasmtime::Result<()> {
+    linker.func_wrap(
+        HOST_MODULE,
+        "http_get",
+        |mut caller: Caller<'_, State>, ptr: i32, len: i32| -> i32 {
+            let (Ok(ptr), Ok(len)) = (usize::try_from(ptr), usize::try_from(len)) else {
+                return ERR_INVALID;
+            };
+            let Some(memory) = memory(&mut caller) else {
+                return ERR_INVALID;
+            };
+            let mut url = vec![0; len];
+            if memory.read(&caller, ptr, &mut url).is_err() {
+                return ERR_INVALID;
+            }
+            let Ok(url) = String::from_utf8(url) else {
+                return ERR_INVALID;
+            };
+            caller.data_mut().http.get(&url)
+        },
+    )?;
+    linker.func_wrap(
+        HOST_MODULE,
+        "http_status",
+        |caller: Caller<'_, State>, handle: i32| -> i32 {
+            caller
+                .data()
+                .http
+                .response(handle)
+                .map_or(ERR_INVALID, |response| i32::from(response.status))
+        },
+    )?;
+    linker.func_wrap(
+        HOST_MODULE,
+        "http_body_len",
+        |caller: Caller<'_, State>, handle: i32| -> i32 {
+            caller
+                .data()
+                .http
+                .response(handle)
+                .map_or(ERR_INVALID, |response| {
+                    i32::try_from(response.body.len()).unwrap_or(ERR_TOO_LARGE)
+                })
+        },
+    )?;
+    linker.func_wrap(
+        HOST_MODULE,
+        "http_read",
+        |mut caller: Caller<'_, State>, handle: i32, offset: i32, ptr: i32, len: i32| -> i32 {
+            let (Ok(offset), Ok(ptr), Ok(len)) = (
+                usize::try_from(offset),
+                usize::try_from(ptr),
+                usize::try_from(len),
+            ) else {
+                return ERR_INVALID;
+            };
+            let Some(memory) = memory(&mut caller) else {
+                return ERR_INVALID;
+            };
+            let (data, state) = memory.data_and_store_mut(&mut caller);
+            let Some(response) = state.http.response(handle) else {
+                return ERR_INVALID;
+            };
+            let Some(body) = response.body.get(offset..) else {
+                return ERR_INVALID;
+            };
+            let body = &body[..len.min(body.len())];
+            let Some(dest) = data.get_mut(ptr..ptr + body.len()) else {
+                return ERR_INVALID;
+            };
+            dest.copy_from_slice(body);
+            i32::try_from(body.len()).unwrap_or(ERR_INVALID)
+        },
+    )?;
+    linker.func_wrap(
+        HOST_MODULE,
+        "http_close",
+        |mut caller: Caller<'_, State>, handle: i32| {
+            if let Ok(index) = usize::try_from(handle) {
+                if let Some(response) = caller.data_mut().http.responses.get_mut(index) {
+                    *response = None;
+                }
+            }
+        },
+    )?;
+    Ok(())
+}
+
+/// Builds the WASI context for the plugin, only giving it access to the folders in its `grants`.
+/// `input` is updated to match what the plugin can see
+fn wasi_context(
+    input: &mut PluginInput,
+    grants: &Grants,
+    stdout: &MemoryOutputPipe,
+    stderr: &MemoryOutputPipe,
+) -> Result<WasiP1Ctx, String> {
+    let mut wasi = WasiCtxBuilder::new();
+    let cache_dir = input.cache_dir.take();
+    if grants.cache_dir {
+        if let Some(cache_dir) = cache_dir {
+            fs::create_dir_all(&cache_dir)
+                .map_err(|err| format!("failed to create cache dir: {err}"))?;
+            wasi.preopened_dir(&cache_dir, GUEST_CACHE_DIR, FsPerms::ReadWrite)
+                .map_err(|err| format!("failed to open cache dir: {err}"))?;
+            input.cache_dir = Some(PathBuf::from(GUEST_CACHE_DIR));
+        }
+    }
+    for dir in &grants.read_dirs {
+        wasi.preopened_dir(dir, dir.to_string_lossy(), FsPerms::ReadOnly)
+            .map_err(|err| format!("failed to open {}: {err}", dir.display()))?;
+    }
+    let json = serde_json::to_vec(input).unwrap_or_default();
+    wasi.stdin(MemoryInputPipe::new(json))
+        .stdout(stdout.clone())
+        .stderr(stderr.clone())
+        .envs(&input.env_vars())
+        .arg(&input.name)
+        .allow_blocking_current_thread(true);
+    Ok(wasi.build_p1())
+}
+
+/// Runs the WASI plugin at `path` in a sandbox, with only the access it has been granted in
+/// `grants`. It is passed `input` the same way as executable plugins, and stopped if it takes
+/// longer than `timeout`. Returns everything the plugin wrote to stdout
+pub fn run(
+    path: &Path,
+    input: &PluginInput,
+    grants: &Grants,
+    timeout: Duration,
+) -> Result<Vec<u8>, String> {
+    let mut config = Config::new();
+    config.consume_fuel(true).epoch_interruption(true);
+    let engine =
+        Engine::new(&config).map_err(|err| format!("failed to start WASM runtime: {err}"))?;
+    let module =
+        Module::from_file(&engine, path).map_err(|err| format!("invalid module: {err}"))?;
+    let mut linker = Linker::new(&engine);
+    p1::add_to_linker_sync(&mut linker, |state: &mut State| &mut state.wasi)
+        .and_then(|()| add_host_api(&mut linker))
+        .map_err(|err| format!("failed to set up WASM runtime: {err}"))?;
+
+    let stdout = MemoryOutputPipe::new(MAX_STDOUT);
+    let stderr = MemoryOutputPipe::new(MAX_STDERR);
+    let mut input = input.clone();
+    let wasi = wasi_context(&mut input, grants, &stdout, &stderr)?;
+    let memory = usize::try_from(grants.memory_bytes().unwrap_or(u64::MAX)).unwrap_or(usize::MAX);
+    let state = State {
+        wasi,
+        limits: StoreLimitsBuilder::new()
+            .memory_size(memory)
+            .trap_on_grow_failure(true)
+            .build(),
+        http: Http::new(grants, timeout),
+    };
+    let mut store = Store::new(&engine, state);
+    store.limiter(|state| &mut state.limits);
+    store
+        .set_fuel(grants.fuel_millions.saturating_mul(1_000_000))
+        .map_err(|err| format!("failed to set CPU limit: {err}"))?;
+    store.set_epoch_deadline(1);
+
+    // Interrupt the plugin once it runs out of time, unless it finishes first
+    let (finished, watch) = mpsc::channel::<()>();
+    let watchdog = thread::spawn(move || {
+        if watch.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+            engine.increment_epoch();
+        }
+    });
+    let result = linker
+        .instantiate(&mut store, &module)
+        .and_then(|instance| instance.get_typed_func::<(), ()>(&mut store, "_start"))
+        .and_then(|start| start.call(&mut store, ()));
+    let _ = finished.send(());
+    let _ = watchdog.join();
+
+    let Err(err) = result else {
+        return Ok(stdout.contents().to_vec());
+    };
+    let reason = match (err.downcast_ref::<I32Exit>(), err.downcast_ref::<Trap>()) {
+        (Some(I32Exit(0)), _) => return Ok(stdout.contents().to_vec()),
+        (Some(I32Exit(code)), _) => format!("exited with code {code}"),
+        (_, Some(Trap::OutOfFuel)) => "used up its CPU limit".to_string(),
+        (_, Some(Trap::Interrupt)) => format!("timed out after {} seconds", timeout.as_secs()),
+        // The root cause skips the wasm backtrace, which means nothing to users
+        _ => format!("crashed: {}", err.root_cause()),
+    };
+    let stderr = String::from_utf8_lossy(&stderr.contents())
+        .trim()
+        .to_string();
+    if stderr.is_empty() {
+        Err(reason)
+    } else {
+        Err(format!("{reason}: {stderr}"))
+    }
+}
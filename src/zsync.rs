@@ -0,0 +1,552 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use futures_util::StreamExt;
+use indicatif::{ProgressBar, ProgressStyle};
+use md4::{Digest, Md4};
+use sha1::Sha1;
+
+use crate::filetype::{Expected, MAGIC_BYTES_NEEDED};
+
+/// How many bytes of the seed file to hold in memory at a time while looking for matching blocks
+const SCAN_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+/// Missing ranges closer together than this many blocks are fetched in a single request, as a few
+/// extra bytes are cheaper than the overhead of another request
+const MERGE_GAP_BLOCKS: u64 = 8;
+
+/// The checksums for a single block of the target file
+#[derive(Debug)]
+struct BlockSum {
+    /// The rolling checksum, truncated to `rsum_bytes`
+    rsum: u32,
+    /// The leading `checksum_bytes` of the MD4 of the block
+    checksum: Vec<u8>,
+}
+
+/// A parsed `.zsync` control file
+#[derive(Debug)]
+pub struct ControlFile {
+    /// Size of each block in bytes
+    blocksize: usize,
+    /// Length of the target file in bytes
+    length: u64,
+    /// How many consecutive blocks need to match before we trust a match
+    seq_matches: usize,
+    /// How many bytes of the rolling checksum are stored per block
+    rsum_bytes: usize,
+    /// Absolute url of the target file
+    url: String,
+    /// Hex encoded SHA-1 of the whole target file
+    sha1: Option<String>,
+    /// When the target file was last modified
+    mtime: Option<DateTime<FixedOffset>>,
+    /// Checksums for every block of the target file
+    blocks: Vec<BlockSum>,
+}
+
+impl ControlFile {
+    /// Parses a `.zsync` control file that was downloaded from `zsync_url`
+    pub fn parse(zsync_url: &str, data: &[u8]) -> Result<Self, String> {
+        let header_end = data
+            .windows(2)
+            .position(|window| window == b"\n\n")
+            .ok_or("zsync control file has no header")?;
+        let header = String::from_utf8_lossy(&data[..header_end]);
+        let headers: HashMap<&str, &str> = header
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect();
+
+        let number = |key: &str| -> Result<u64, String> {
+            headers
+                .get(key)
+                .ok_or_else(|| format!("zsync control file is missing '{key}'"))?
+                .parse()
+                .map_err(|_| format!("zsync control file has an invalid '{key}'"))
+        };
+        let blocksize = usize::try_from(number("Blocksize")?).map_err(|err| err.to_string())?;
+        let length = number("Length")?;
+        if blocksize == 0 {
+            return Err("zsync control file has a blocksize of zero".into());
+        }
+
+        let hash_lengths: Vec<usize> = headers
+            .get("Hash-Lengths")
+            .ok_or("zsync control file is missing 'Hash-Lengths'")?
+            .split(',')
+            .map(|value| value.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| "zsync control file has an invalid 'Hash-Lengths'")?;
+        let [seq_matches, rsum_bytes, checksum_bytes] = hash_lengths[..] else {
+            return Err("zsync control file has an invalid 'Hash-Lengths'".into());
+        };
+        if !(1..=2).contains(&seq_matches)
+            || !(1..=4).contains(&rsum_bytes)
+            || !(3..=16).contains(&checksum_bytes)
+        {
+            return Err("zsync control file has an invalid 'Hash-Lengths'".into());
+        }
+
+        // We only support fetching the uncompressed file, not the gzipped Z-URL variant
+        let url = headers
+            .get("URL")
+            .ok_or("zsync control file has no uncompressed 'URL', which is required")?;
+        let url = reqwest::Url::parse(zsync_url)
+            .and_then(|base| base.join(url))
+            .map_err(|err| format!("zsync control file has an invalid 'URL': {err}"))?
+            .to_string();
+
+        let block_count =
+            usize::try_from(length.div_ceil(blocksize as u64)).map_err(|err| err.to_string())?;
+        let record_len = rsum_bytes + checksum_bytes;
+        let body = &data[header_end + 2..];
+        if body.len() < block_count * record_len {
+            return Err("zsync control file is truncated".into());
+        }
+        let blocks = body
+            .chunks_exact(record_len)
+            .take(block_count)
+            .map(|record| {
+                let mut rsum = [0u8; 4];
+                rsum[4 - rsum_bytes..].copy_from_slice(&record[..rsum_bytes]);
+                BlockSum {
+                    rsum: u32::from_be_bytes(rsum),
+                    checksum: record[rsum_bytes..].to_vec(),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            blocksize,
+            length,
+            seq_matches,
+            rsum_bytes,
+            url,
+            sha1: headers.get("SHA-1").map(|sha| sha.to_ascii_lowercase()),
+            mtime: headers
+                .get("MTime")
+                .and_then(|mtime| DateTime::parse_from_rfc2822(mtime).ok()),
+            blocks,
+        })
+    }
+
+    /// Returns if the file at `path` is already the target file. It has to be the right length and
+    /// match the SHA-1. Only control files without a SHA-1 fall back to trusting a file that was
+    /// modified after the target
+    fn is_current(&self, path: &Path) -> Result<bool, String> {
+        let meta = fs::metadata(path).map_err(|err| format!("Failed to read zsync seed: {err}"))?;
+        if meta.len() != self.length {
+            return Ok(false);
+        }
+        let Some(sha1) = &self.sha1 else {
+            let modified = meta.modified().ok().map(DateTime::<Utc>::from);
+            return Ok(self
+                .mtime
+                .zip(modified)
+                .is_some_and(|(mtime, modified)| modified >= mtime));
+        };
+        let mut file =
+            File::open(path).map_err(|err| format!("Failed to open zsync seed: {err}"))?;
+        Ok(sha1_hex(&mut file, |_| {})? == *sha1)
+    }
+
+    /// Returns the mask to apply to a full rolling checksum to compare it to a stored one
+    const fn rsum_mask(&self) -> u32 {
+        if self.rsum_bytes >= 4 {
+            u32::MAX
+        } else {
+            (1 << (8 * self.rsum_bytes)) - 1
+        }
+    }
+
+    /// Returns the number of bytes of the target file contained in the block at `index`
+    fn block_len(&self, index: usize) -> usize {
+        let start = index as u64 * self.blocksize as u64;
+        usize::try_from(u64::min(self.blocksize as u64, self.length - start)).unwrap_or_default()
+    }
+
+    /// Returns if `data` has the same MD4 checksum as the block at `index`
+    fn checksum_matches(&self, index: usize, data: &[u8]) -> bool {
+        let block = &self.blocks[index];
+        Md4::digest(data)[..block.checksum.len()] == block.checksum[..]
+    }
+}
+
+/// The rsync style rolling checksum used by zsync
+#[derive(Debug, Clone, Copy, Default)]
+struct RollingSum {
+    a: u16,
+    b: u16,
+}
+
+impl RollingSum {
+    fn new(data: &[u8]) -> Self {
+        let len = data.len();
+        data.iter()
+            .enumerate()
+            .fold(Self::default(), |sum, (i, byte)| Self {
+                a: sum.a.wrapping_add(u16::from(*byte)),
+                #[allow(clippy::cast_possible_truncation)]
+                b: sum
+                    .b
+                    .wrapping_add(((len - i) as u16).wrapping_mul(u16::from(*byte))),
+            })
+    }
+
+    /// Moves the window forward one byte, dropping `old` and adding `new`
+    #[allow(clippy::cast_possible_truncation)]
+    fn roll(&mut self, old: u8, new: u8, blocksize: usize) {
+        self.a = self
+            .a
+            .wrapping_sub(u16::from(old))
+            .wrapping_add(u16::from(new));
+        self.b = self
+            .b
+            .wrapping_sub((blocksize as u16).wrapping_mul(u16::from(old)))
+            .wrapping_add(self.a);
+    }
+
+    const fn value(self) -> u32 {
+        ((self.a as u32) << 16) | self.b as u32
+    }
+}
+
+/// Looks for a previous version of the file at `path` to use as a zsync seed. This is either the
+/// file itself, or the newest file in the same folder with the same extension and the same name
+/// up to the last `_`, as used by Kiwix for dated revisions such as `wikipedia_en_all_2024-01.zim`
+pub fn find_seed(path: &Path) -> Option<PathBuf> {
+    if path.is_file() {
+        return Some(path.to_path_buf());
+    }
+    let name = path.file_name()?.to_str()?;
+    let extension = path.extension()?.to_str()?;
+    let prefix = &name[..=name.rfind('_')?];
+    path.parent()?
+        .read_dir()
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let entry_name = entry.file_name();
+            let entry_name = entry_name.to_string_lossy();
+            entry_name.starts_with(prefix)
+                && Path::new(entry_name.as_ref())
+                    .extension()
+                    .is_some_and(|ext| ext == extension)
+                && entry.path().is_file()
+        })
+        .max_by_key(|entry| entry.metadata().and_then(|meta| meta.modified()).ok())
+        .map(|entry| entry.path())
+}
+
+/// Scans `seed` for blocks of the target file and writes any it finds into `out`. Returns which
+/// blocks of the target were found
+fn copy_known_blocks(
+    control: &ControlFile,
+    seed: &Path,
+    out: &mut File,
+) -> Result<Vec<bool>, String> {
+    let blocksize = control.blocksize;
+    let mask = control.rsum_mask();
+    let mut lookup: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in control.blocks.iter().enumerate() {
+        lookup.entry(block.rsum).or_default().push(i);
+    }
+    let mut found = vec![false; control.blocks.len()];
+
+    let mut seed_file =
+        File::open(seed).map_err(|err| format!("Failed to open zsync seed: {err}"))?;
+    let seed_len = seed_file.metadata().map_or(0, |meta| meta.len());
+    let pb = ProgressBar::new(seed_len);
+    #[allow(clippy::literal_string_with_formatting_args)]
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+        .unwrap()
+        .progress_chars("#>-"));
+    pb.set_message(format!("Scanning {} for reusable blocks", seed.display()));
+
+    // The buffer holds the current window plus everything after it that has been read so far.
+    // Once the seed has been read completely it's padded with a block of zeros, as zsync does
+    let mut buffer: Vec<u8> = Vec::with_capacity(SCAN_BUFFER_SIZE + blocksize);
+    let mut chunk = vec![0u8; SCAN_BUFFER_SIZE];
+    let mut eof = false;
+    let mut offset: u64 = 0; // Position in the seed of buffer[0]
+    let mut pos = 0; // Position in the buffer of the window start
+    let mut sum: Option<RollingSum> = None;
+
+    loop {
+        // Make sure the buffer has a full window, plus the following block for sequential matches
+        if !eof && buffer.len() - pos < 2 * blocksize {
+            buffer.drain(..pos);
+            offset += pos as u64;
+            pos = 0;
+            let read = seed_file
+                .read(&mut chunk)
+                .map_err(|err| format!("Failed to read zsync seed: {err}"))?;
+            if read == 0 {
+                eof = true;
+                buffer.resize(buffer.len() + blocksize, 0);
+            } else {
+                buffer.extend_from_slice(&chunk[..read]);
+            }
+            pb.set_position(offset);
+            continue;
+        }
+        if pos + blocksize > buffer.len() {
+            break;
+        }
+
+        let window = &buffer[pos..pos + blocksize];
+        let current = *sum.get_or_insert_with(|| RollingSum::new(window));
+        let mut matched = false;
+        if let Some(candidates) = lookup.get(&(current.value() & mask)) {
+            let digest = Md4::digest(window);
+            for &index in candidates {
+                let block = &control.blocks[index];
+                if found[index] || digest[..block.checksum.len()] != block.checksum[..] {
+                    continue;
+                }
+                // Short checksums need the following block to match as well to be trusted
+                if control.seq_matches > 1 && index + 1 < control.blocks.len() {
+                    let Some(next) = buffer.get(pos + blocksize..pos + 2 * blocksize) else {
+                        continue;
+                    };
+                    let next_block = &control.blocks[index + 1];
+                    if RollingSum::new(next).value() & mask != next_block.rsum
+                        || !control.checksum_matches(index + 1, next)
+                    {
+                        continue;
+                    }
+                }
+                out.seek(SeekFrom::Start(index as u64 * blocksize as u64))
+                    .and_then(|_| out.write_all(&window[..control.block_len(index)]))
+                    .map_err(|err| format!("Failed to write zsync output: {err}"))?;
+                found[index] = true;
+                matched = true;
+            }
+        }
+
+        if matched {
+            // Skip past the matched block and start a fresh checksum
+            pos += blocksize;
+            sum = None;
+        } else {
+            let old = buffer[pos];
+            let new = buffer.get(pos + blocksize).copied().unwrap_or_default();
+            if let Some(sum) = &mut sum {
+                sum.roll(old, new, blocksize);
+            }
+            pos += 1;
+        }
+    }
+
+    pb.finish_with_message(format!(
+        "Reusing {} of {} blocks from {}",
+        found.iter().filter(|found| **found).count(),
+        found.len(),
+        seed.display()
+    ));
+    Ok(found)
+}
+
+/// Returns the byte ranges of the target file that still need to be fetched, merging ranges that
+/// are close together
+fn missing_ranges(control: &ControlFile, found: &[bool]) -> Vec<(u64, u64)> {
+    let blocksize = control.blocksize as u64;
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for (index, _) in found.iter().enumerate().filter(|(_, found)| !**found) {
+        let start = index as u64 * blocksize;
+        let end = u64::min(start + blocksize, control.length);
+        match ranges.last_mut() {
+            Some(last) if start <= last.1 + MERGE_GAP_BLOCKS * blocksize => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    ranges
+}
+
+/// Builds the file at `path` from the zsync control file at `zsync_url`, reusing blocks from the
+/// `seed` file and only fetching the parts that have changed. Like `download::get_file` the
+/// output is written to `temp_path` and only moved into place once it has been verified. Nothing
+/// is fetched if the seed is the file at `path` and it is already up to date
+pub async fn get_file(
+    client: &reqwest::Client,
+    zsync_url: &str,
+    seed: &Path,
+    path: &str,
+    temp_path: &str,
+    expected: &Expected,
+) -> Result<(), String> {
+    let res = client
+        .get(zsync_url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|err| format!("Failed to GET from '{zsync_url}': {err}"))?;
+    let data = res
+        .bytes()
+        .await
+        .map_err(|err| format!("Failed to read '{zsync_url}': {err}"))?;
+    let control = ControlFile::parse(zsync_url, &data)?;
+    if seed == Path::new(path) && control.is_current(seed)? {
+        println!("{path} is already up to date");
+        return Ok(());
+    }
+
+    let mut out =
+        File::create(temp_path).map_err(|_| format!("Failed to create file '{temp_path}'"))?;
+    out.set_len(control.length)
+        .map_err(|err| format!("Failed to allocate '{temp_path}': {err}"))?;
+
+    let result = fetch_into(client, &control, seed, &mut out, expected).await;
+    drop(out);
+    if let Err(err) = result {
+        let _ = fs::remove_file(temp_path);
+        return Err(err);
+    }
+
+    fs::rename(temp_path, path).map_err(|_| format!("Failed to move '{temp_path}' to '{path}'"))
+}
+
+/// Fills `out` with the target file described by `control` and verifies the result
+async fn fetch_into(
+    client: &reqwest::Client,
+    control: &ControlFile,
+    seed: &Path,
+    out: &mut File,
+    expected: &Expected,
+) -> Result<(), String> {
+    let found = copy_known_blocks(control, seed, out)?;
+    let ranges = missing_ranges(control, &found);
+    let total: u64 = ranges.iter().map(|(start, end)| end - start).sum();
+
+    let pb = ProgressBar::new(total);
+    #[allow(clippy::literal_string_with_formatting_args)]
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+        .unwrap()
+        .progress_chars("#>-"));
+    pb.set_message(format!(
+        "Downloading {} changed range(s) from {}",
+        ranges.len(),
+        control.url
+    ));
+
+    let mut downloaded = 0;
+    for (start, end) in ranges {
+        let res = client
+            .get(&control.url)
+            .header(reqwest::header::RANGE, format!("bytes={start}-{}", end - 1))
+            .send()
+            .await
+            .map_err(|err| format!("Failed to GET from '{}': {err}", control.url))?;
+        if res.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Err(format!(
+                "Server does not support range requests for '{}', returned {}",
+                control.url,
+                res.status()
+            ));
+        }
+        // A server that ignores part of the range would otherwise put blocks in the wrong place
+        let content_range = res
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok());
+        if parse_content_range(content_range) != Some((start, end - 1)) {
+            return Err(format!(
+                "Server returned range {} of '{}' when {start}-{} was requested",
+                content_range.unwrap_or("none"),
+                control.url,
+                end - 1
+            ));
+        }
+        out.seek(SeekFrom::Start(start))
+            .map_err(|err| format!("Failed to write zsync output: {err}"))?;
+        let mut written = 0;
+        let mut stream = res.bytes_stream();
+        while let Some(item) = stream.next().await {
+            let chunk = item.or(Err("Error while downloading file"))?;
+            let len = usize::try_from(u64::min(chunk.len() as u64, end - start - written))
+                .unwrap_or_default();
+            out.write_all(&chunk[..len])
+                .or(Err("Error while writing to file"))?;
+            written += len as u64;
+            downloaded += len as u64;
+            pb.set_position(downloaded);
+        }
+        if written < end - start {
+            return Err(format!(
+                "Range {start}-{end} of '{}' ended early",
+                control.url
+            ));
+        }
+    }
+
+    // Make sure the assembled file is what we were after
+    pb.set_message("Verifying assembled file");
+    out.flush().or(Err("Error while writing to file"))?;
+    out.seek(SeekFrom::Start(0))
+        .or(Err("Error while reading assembled file"))?;
+    let mut head: Vec<u8> = Vec::new();
+    let actual = sha1_hex(out, |buffer| {
+        if head.len() < MAGIC_BYTES_NEEDED {
+            let needed = usize::min(MAGIC_BYTES_NEEDED - head.len(), buffer.len());
+            head.extend_from_slice(&buffer[..needed]);
+        }
+    })?;
+    if let Some(sha1) = &control.sha1 {
+        if &actual != sha1 {
+            pb.abandon_with_message("zsync output failed SHA-1 verification");
+            return Err(format!(
+                "Assembled file has SHA-1 {actual} but {sha1} was expected"
+            ));
+        }
+    }
+    expected.check_magic(&head, control.length)?;
+
+    pb.finish_with_message(format!(
+        "Assembled {} reusing {} of {} blocks",
+        control.url,
+        found.iter().filter(|found| **found).count(),
+        found.len()
+    ));
+    Ok(())
+}
+
+/// Returns the hex encoded SHA-1 of everything left to read in `file`, passing each buffer read to
+/// `inspect` along the way
+fn sha1_hex(file: &mut File, mut inspect: impl FnMut(&[u8])) -> Result<String, String> {
+    let mut hasher = Sha1::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .or(Err("Error while reading file to hash"))?;
+        if read == 0 {
+            break;
+        }
+        inspect(&buffer[..read]);
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        }))
+}
+
+/// Returns the first and last byte of a `Content-Range` header such as `bytes 0-1023/4096`
+fn parse_content_range(header: Option<&str>) -> Option<(u64, u64)> {
+    let range = header?.trim().strip_prefix("bytes ")?;
+    let (range, _total) = range.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    Some((first.trim().parse().ok()?, last.trim().parse().ok()?))
+}